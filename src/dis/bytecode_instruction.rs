@@ -27,6 +27,7 @@ pub struct Bci {
     pub index: usize,
     pub op: u8,
     pub registers: Registers,
+    pub patched: bool, //the op was an interpreter or JIT variant, normalized back to its canonical op.
 }
 
 impl fmt::Display for Bci {
@@ -57,6 +58,7 @@ impl Bci {
             index: index,
             op: op,
             registers: Registers::new(a, c, b),
+            patched: false,
         }
     }
 
@@ -64,10 +66,24 @@ impl Bci {
     pub fn c(&self) -> u8   { self.registers.c }
     pub fn b(&self) -> u8   { self.registers.b }
     pub fn d(&self) -> u16  { self.registers.d }
+    pub fn sd(&self) -> i16 { self.registers.d as i16 } //signed D, as used by KSHORT.
+
+    ///Signed jump distance from the next instruction. Jump offsets are stored biased by 0x8000.
+    pub fn jump_offset(&self) -> i32 {
+        self.d() as i32 - 0x8000
+    }
 
     pub fn get_jump_target(&self) -> u32 {
         assert!(self.is_jump(), "Attempt to get jump target of bci that is not a jump: {}", self);
-        1 + self.index as u32 + ((self.b() as u32) << 8 | self.c() as u32) - 0x8000
+        let target = 1 + self.index as i64 + self.jump_offset() as i64;
+        assert!(target >= 0, "Jump target before the first instruction: {}", self);
+        target as u32
+    }
+
+    ///Points a jump at the given instruction, keeping B and C in step with D.
+    pub fn set_jump_target(&mut self, target: usize) {
+        let d = (target as i64 - 1 - self.index as i64 + 0x8000) as u16;
+        self.registers = Registers::new(self.a(), d as u8, (d >> 8) as u8);
     }
 
     pub fn get_operation_name(&self) -> String {
@@ -207,7 +223,7 @@ impl Bci {
         "FUNCC",
         "FUNCCW", //85-92 funcs
 
-        "GOTO", //93: Not part of the original LJ opcodes, but I added this here to rename unconditional jmp (and potentially UCLO) instructions as simply goto instructions.
+        "GOTO", //93: Not part of the original LJ opcodes, but I added this here to rename unconditional jmp instructions as simply goto instructions.
         "ITERJ", //94: Not part of LJ opcodes. Used to mark JMPs which are associated with ITERC.
     ];
 }
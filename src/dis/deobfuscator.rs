@@ -0,0 +1,219 @@
+// Removes the junk obfuscators add to the control flow, before the jumps are marked and the instructions blocked.
+
+use std::collections::BTreeSet;
+
+use crate::dis::{
+    bytecode_instruction::Bci,
+    prototyper::VarInfo,
+};
+
+pub struct Deobfuscator{}
+impl Deobfuscator {
+    ///! Folds constant conditions, threads jump chains and drops junk jumps and unreachable instructions until nothing changes.
+    ///! The variables' pcs are moved along with the instructions they refer to.
+    pub fn clean(bcis: &mut Vec<Bci>, var_info: &mut [VarInfo]) {
+        loop {
+            let folded = Deobfuscator::fold_conditions(bcis);
+            let threaded = Deobfuscator::thread_jumps(bcis);
+            let removed = Deobfuscator::remove_dead(bcis, var_info);
+            if !folded && !threaded && !removed { break; }
+        }
+    }
+
+    ///! A comparison against a primitive just loaded by KPRI always goes the same way. The comparison becomes a JMP +0 and
+    ///! its JMP is either kept as an unconditional jump or also made a JMP +0.
+    fn fold_conditions(bcis: &mut [Bci]) -> bool {
+        let targets = Deobfuscator::jump_targets(bcis);
+        let mut folded = false;
+        for i in 1..bcis.len().saturating_sub(1) {
+            let (kpri, cmp) = (&bcis[i - 1], &bcis[i]);
+            //Anything jumping to the comparison could bring another value, anything jumping to its JMP still needs it.
+            if kpri.op != 41 || targets.contains(&i) || targets.contains(&(i + 1)) || bcis[i + 1].op != 84 { continue; }
+            let value = kpri.d(); //0 is nil, 1 false and 2 true.
+            let taken = match cmp.op {
+                10 if cmp.a() == kpri.a() => value == cmp.d(), //ISEQP
+                11 if cmp.a() == kpri.a() => value != cmp.d(), //ISNEP
+                14 if cmp.d() == kpri.a() as u16 => value == 2, //IST
+                15 if cmp.d() == kpri.a() as u16 => value != 2, //ISF
+                _ => continue,
+            };
+            Deobfuscator::make_junk(&mut bcis[i]);
+            if !taken { Deobfuscator::make_junk(&mut bcis[i + 1]); }
+            folded = true;
+        }
+        folded
+    }
+
+    ///! Points every JMP whose target is another unconditional JMP at the end of the chain. A JMP to an ITERC or ITERN
+    ///! opens a generic for loop and is kept as the end of the chain, so no other jump is mistaken for it.
+    fn thread_jumps(bcis: &mut [Bci]) -> bool {
+        let mut threaded = false;
+        for i in 0..bcis.len() {
+            if bcis[i].op != 84 { continue; }
+            let mut target = bcis[i].get_jump_target() as usize;
+            let mut seen: BTreeSet<usize> = BTreeSet::from([i]);
+            while Deobfuscator::is_unconditional_jmp(bcis, target) && !Deobfuscator::opens_iterator(bcis, target) && seen.insert(target) {
+                target = bcis[target].get_jump_target() as usize;
+            }
+            if target != bcis[i].get_jump_target() as usize {
+                bcis[i].set_jump_target(target);
+                threaded = true;
+            }
+        }
+        threaded
+    }
+
+    ///! Drops the instructions that cannot be reached from the first, and JMP +0 that is not the jump of a comparison.
+    ///! The JMP +0 opening a generic for loop with an empty body is what marks the loop, it stays.
+    fn remove_dead(bcis: &mut Vec<Bci>, var_info: &mut [VarInfo]) -> bool {
+        let reachable = Deobfuscator::reachable(bcis);
+        let is_junk = |i: usize| Deobfuscator::is_unconditional_jmp(bcis, i)
+            && bcis[i].jump_offset() == 0
+            && !Deobfuscator::opens_iterator(bcis, i);
+        let keep: Vec<bool> = (0..bcis.len()).map(|i| reachable.contains(&i) && !is_junk(i)).collect();
+        if keep.iter().all(|k| *k) { return false; }
+
+        //Index each instruction moves to. A removed instruction maps to the next one kept, which takes its place.
+        let mut moved_to: Vec<usize> = vec![0; bcis.len() + 1];
+        for i in 0..bcis.len() {
+            moved_to[i + 1] = moved_to[i] + keep[i] as usize;
+        }
+        for (i, mut bci) in std::mem::take(bcis).into_iter().enumerate() {
+            if !keep[i] { continue; }
+            let target = if bci.is_jump() { Some(moved_to[bci.get_jump_target() as usize]) } else { None };
+            bci.index = moved_to[i];
+            if let Some(target) = target { bci.set_jump_target(target); }
+            bcis.push(bci);
+        }
+
+        //pc 0 is the function header, instruction i is at pc i+1.
+        let move_pc = |pc: u32| if pc == 0 { 0 } else { moved_to[(pc as usize - 1).min(keep.len())] as u32 + 1 };
+        for var in var_info.iter_mut() {
+            var.start_pc = move_pc(var.start_pc);
+            var.end_pc = move_pc(var.end_pc);
+        }
+        true
+    }
+
+    fn reachable(bcis: &[Bci]) -> BTreeSet<usize> {
+        let mut reachable: BTreeSet<usize> = BTreeSet::new();
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if i >= bcis.len() || !reachable.insert(i) { continue; }
+            let bci = &bcis[i];
+            if bci.is_jump() {
+                stack.push(bci.get_jump_target() as usize);
+            }
+            let falls_through = match bci.op {
+                0..=15 => { stack.push(i + 2); true }, //the comparison skips its JMP when it fails.
+                48 | 68 => false, //UCLO, ISNEXT
+                84 | 93 | 94 => false, //JMP, GOTO, ITERJ
+                63 | 64 | 69..=72 => false, //CALLMT, CALLT, RET*
+                _ => true,
+            };
+            if falls_through { stack.push(i + 1); }
+        }
+        reachable
+    }
+
+    fn jump_targets(bcis: &[Bci]) -> BTreeSet<usize> {
+        bcis.iter().filter(|bci| bci.is_jump()).map(|bci| bci.get_jump_target() as usize).collect()
+    }
+
+    ///! True for a JMP that is not the jump of a comparison.
+    fn is_unconditional_jmp(bcis: &[Bci], i: usize) -> bool {
+        bcis.get(i).is_some_and(|bci| bci.op == 84) && (i == 0 || bcis[i - 1].op >= 16)
+    }
+
+    ///! True for a JMP to the ITERC or ITERN of a generic for loop.
+    fn opens_iterator(bcis: &[Bci], i: usize) -> bool {
+        let target = bcis[i].get_jump_target() as usize;
+        bcis.get(target).is_some_and(|bci| bci.op == 65 || bci.op == 66)
+    }
+
+    ///! Turns the instruction into a JMP to the next one.
+    fn make_junk(bci: &mut Bci) {
+        bci.op = 84;
+        bci.registers.a = 0;
+        bci.set_jump_target(bci.index + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dis::prototyper::Prototyper;
+
+    fn jump(index: usize, target: usize) -> Bci {
+        let d = (target as i64 - 1 - index as i64 + 0x8000) as u16;
+        Bci::new(index, 84, 0, d as u8, (d >> 8) as u8)
+    }
+
+    #[test]
+    fn test_clean() {
+        let mut bcis = vec![
+            Bci::new(0, 41, 0, 2, 0),  //KPRI 0 true
+            Bci::new(1, 10, 0, 2, 0),  //ISEQP 0 true, always taken
+            jump(2, 5),
+            Bci::new(3, 39, 1, 1, 0),  //KSHORT, dead
+            jump(4, 7),                //dead
+            jump(5, 6),                //JMP +0
+            jump(6, 8),                //start of a chain
+            Bci::new(7, 39, 1, 2, 0),  //KSHORT, dead
+            jump(8, 9),
+            Bci::new(9, 71, 0, 1, 0),  //RET0
+        ];
+        let mut var_info = vec![VarInfo{ name: "x".to_string(), start_pc: 1, end_pc: 10 }];
+        Deobfuscator::clean(&mut bcis, &mut var_info);
+
+        let ops: Vec<u8> = bcis.iter().map(|b| b.op).collect();
+        assert!(ops == vec![41, 71], "{:?}", ops);
+        assert!(bcis[1].index == 1);
+        assert!(var_info[0].start_pc == 1 && var_info[0].end_pc == 2);
+    }
+
+    #[test]
+    fn test_fold_keeps_jumped_to_conditions() {
+        let mut bcis = vec![
+            Bci::new(0, 41, 0, 1, 0),  //KPRI 0 false
+            Bci::new(1, 10, 0, 2, 0),  //ISEQP 0 true, never taken
+            jump(2, 4),
+            jump(3, 2),                //reaches the comparison's JMP from elsewhere
+            Bci::new(4, 71, 0, 1, 0),  //RET0
+        ];
+        Deobfuscator::clean(&mut bcis, &mut []);
+        let ops: Vec<u8> = bcis.iter().map(|b| b.op).collect();
+        assert!(ops == vec![41, 10, 84, 84, 71], "{:?}", ops);
+        assert!(bcis[3].get_jump_target() == 2);
+    }
+
+    #[test]
+    fn test_clean_keeps_empty_generic_for() {
+        //for _ in it do end: the JMP to the ITERC is a JMP +0 and ITERL loops back to the ITERC.
+        let mut iterl = Bci::new(2, 78, 3, 0, 0);
+        iterl.set_jump_target(1);
+        let mut bcis = vec![
+            jump(0, 1),
+            Bci::new(1, 65, 3, 2, 3),  //ITERC
+            iterl,
+            Bci::new(3, 71, 0, 1, 0),  //RET0
+        ];
+        Deobfuscator::clean(&mut bcis, &mut []);
+        let ops: Vec<u8> = bcis.iter().map(|b| b.op).collect();
+        assert!(ops == vec![84, 65, 78, 71], "{:?}", ops);
+    }
+
+    #[test]
+    fn test_clean_keeps_real_conditions() {
+        let mut plain = Prototyper::new("dec.lua");
+        let mut ptr = Prototyper::new("dec.lua");
+        ptr.deobfuscate = true;
+        while let (Some(expected), Some(pt)) = (plain.next(), ptr.next()) {
+            let comparisons = |bcis: &Vec<Bci>| bcis.iter().filter(|b| b.op < 16).count();
+            assert!(pt.instructions.len() <= expected.instructions.len());
+            assert!(comparisons(&pt.instructions) == comparisons(&expected.instructions));
+            assert!(pt.instructions.iter().enumerate().all(|(i, b)| b.index == i));
+            assert!(pt.instructions.iter().filter(|b| b.is_jump()).all(|b| (b.get_jump_target() as usize) < pt.instructions.len()));
+        }
+    }
+}
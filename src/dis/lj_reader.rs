@@ -45,19 +45,29 @@ impl LJReader {
         value
     }
 
-    ///Reads a luajit number constant.
+    ///Reads a uleb128 which can carry 33 bits, as used by number constants.
+    pub fn read_uleb33(&mut self) -> u64 {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte();
+            value |= ((byte & 127u8) as u64) << shift;
+            shift += 7;
+            if byte & 128u8 == 0 { break; }
+        }
+        value
+    }
+
+    ///Reads a luajit number constant. The lowest bit tells integers (int32) from doubles (lo, hi words).
     pub fn read_kn(&mut self) -> LuaValue {
-        let mut kn_a = self.read_uleb();
+        let kn_a = self.read_uleb33();
         let is_a_double = (kn_a & 1) > 0;
-        kn_a >>= 1;
+        let lo = (kn_a >> 1) as u32;
         if is_a_double {
-            let kn_b = self.read_uleb();
-            let mut kn_union: u64 = kn_a as u64;
-            kn_union <<= 16;
-            kn_union |= kn_b as u64;
-            return LuaValue::Double(kn_union as f64)
+            let hi = self.read_uleb();
+            LuaValue::Double(f64::from_bits((hi as u64) << 32 | lo as u64))
         } else {
-            return LuaValue::UInt(kn_a)
+            LuaValue::SInt(lo as i32)
         }
     }
 
@@ -67,8 +77,8 @@ impl LJReader {
         match type_byte {
             0   => LuaValue::ChildProto, //signal that the prototyper needs to handle a child prototype by popping from the id stack and setting up parent/child relationship between the 2 prototypes.
             1   => LuaValue::Table(self.read_lua_table()), //add table constant -> array_part_len = uleb, hash_part_len = uleb, see TableConstant for more details.
-            2   => LuaValue::SInt(self.read_uleb() as i32),
-            3   => LuaValue::UInt(self.read_uleb()),
+            2   => LuaValue::I64(self.read_u64() as i64),
+            3   => LuaValue::U64(self.read_u64()),
             4   => LuaValue::ComplexNum(self.read_complex_lua_number()),
             x   => LuaValue::Str(self.read_lua_string((x-5) as usize)),
         }
@@ -81,8 +91,11 @@ impl LJReader {
             0   => LuaValue::Nil,
             1   => LuaValue::False,
             2   => LuaValue::True,
-            3   => LuaValue::UInt(self.read_uleb()),
-            4   => LuaValue::ComplexNum(self.read_complex_lua_number()),
+            3   => LuaValue::SInt(self.read_uleb() as i32),
+            4   => {
+                let (lo, hi) = (self.read_uleb(), self.read_uleb());
+                LuaValue::Double(f64::from_bits((hi as u64) << 32 | lo as u64))
+            },
             x   => LuaValue::Str(self.read_lua_string((x-5) as usize)),
         }
     }
@@ -116,8 +129,17 @@ impl LJReader {
         }
     }
 
-    fn read_complex_lua_number(&mut self) -> (u32, u32) {
-        (self.read_uleb(), self.read_uleb()) //I think that it is in the form: XeY where X = first uleb, Y = second uleb. X may be a 32bit float and Y may be an integer.
+    ///Reads a 64 bit cdata integer stored as lo, hi ulebs.
+    fn read_u64(&mut self) -> u64 {
+        let (lo, hi) = (self.read_uleb(), self.read_uleb());
+        (hi as u64) << 32 | lo as u64
+    }
+
+    ///Reads a complex cdata number stored as two doubles (real, imaginary), each as lo, hi ulebs.
+    fn read_complex_lua_number(&mut self) -> (f64, f64) {
+        let re = f64::from_bits(self.read_u64());
+        let im = f64::from_bits(self.read_u64());
+        (re, im)
     }
 
     fn read_lua_string(&mut self, len: usize) -> String {
@@ -146,4 +168,28 @@ mod tests {
     fn test_new_ljr() {
         let ljr = LJReader::new(MOCK_PT.to_vec());
     }
+
+    #[test]
+    fn test_read_kn() {
+        //42 as an integer, then 0.5 as a double (lo = 0, hi = 0x3fe00000).
+        let mut ljr = LJReader::new(vec![0x54, 0x01, 0x80, 0x80, 0x80, 0xff, 0x03]);
+        assert!(ljr.read_kn() == LuaValue::SInt(42));
+        assert!(ljr.read_kn() == LuaValue::Double(0.5));
+    }
+
+    #[test]
+    fn test_read_kgc_cdata() {
+        //16ULL, -1LL, then 1i (re = 0, im = 1.0 with hi = 0x3ff00000).
+        let mut ljr = LJReader::new(vec![
+            0x03, 0x10, 0x00,
+            0x02, 0xff, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0xff, 0x0f,
+            0x04, 0x00, 0x00, 0x00, 0x80, 0x80, 0xc0, 0xff, 0x03,
+        ]);
+        assert!(ljr.read_kgc() == LuaValue::U64(16));
+        assert!(ljr.read_kgc() == LuaValue::I64(-1));
+        let complex = ljr.read_kgc();
+        assert!(complex == LuaValue::ComplexNum((0.0, 1.0)));
+        assert!(complex.to_string() == "1i");
+        assert!(LuaValue::U64(16).to_string() == "16ULL");
+    }
 }
\ No newline at end of file
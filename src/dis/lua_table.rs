@@ -3,7 +3,7 @@ use std::vec::Vec;
 use std::fmt;
 use std::fmt::Formatter;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum LuaValue {
     Empty,
     Nil,
@@ -12,8 +12,9 @@ pub enum LuaValue {
     True,
     False,
     SInt(i32),
-    UInt(u32),
-    ComplexNum((u32, u32)),
+    I64(i64), //FFI int64_t cdata.
+    U64(u64), //FFI uint64_t cdata.
+    ComplexNum((f64, f64)), //FFI complex cdata as real, imaginary.
     Str(String),
     Double(f64),
 }
@@ -35,8 +36,10 @@ impl fmt::Display for LuaValue {
             LuaValue::True              => v = "true".to_string(),
             LuaValue::False             => v = "false".to_string(),
             LuaValue::SInt(i)           => v = i.to_string(), 
-            LuaValue::UInt(u)           => v = u.to_string(), 
-            LuaValue::ComplexNum(n)     => v = String::from(&format!("{}+({})i", n.0, n.1)), 
+            LuaValue::I64(i)            => v = format!("{}LL", i),
+            LuaValue::U64(u)            => v = format!("{}ULL", u),
+            LuaValue::ComplexNum(n) if n.0 == 0.0 => v = format!("{}i", n.1),
+            LuaValue::ComplexNum(n)     => v = format!("({}{:+}i)", n.0, n.1),
             LuaValue::Str(s)            => v = String::from(s), 
             LuaValue::Double(d)         => v = d.to_string(), 
         }
@@ -45,21 +48,21 @@ impl fmt::Display for LuaValue {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ArrayPart {
     pub values: Vec<LuaValue>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HashPart {
     pub keys: Vec<LuaValue>,
     pub values: Vec<LuaValue>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LuaTable {
-    array_part: ArrayPart,
-    hash_part: HashPart,
+    pub array_part: ArrayPart,
+    pub hash_part: HashPart,
 }
 
 impl LuaTable {
@@ -2,4 +2,6 @@ pub mod bytecode_instruction;
 pub mod prototyper;
 mod lj_file_reader;
 mod lj_reader;
-mod lua_table;
\ No newline at end of file
+pub mod lua_table;
+mod verifier;
+mod deobfuscator;
\ No newline at end of file
@@ -8,12 +8,14 @@ use crate::{
         lj_reader::LJReader,
         bytecode_instruction::Bci,
         lua_table::*,
+        verifier::Verifier,
+        deobfuscator::Deobfuscator,
     },
 };
 
 pub struct PrototypeHeader {
     pub id: usize,
-    pub flags: u8,
+    pub flags: ProtoFlags,
     pub num_params: u8,
     pub frame_size: u8,
     pub size_uv: u8,
@@ -23,12 +25,71 @@ pub struct PrototypeHeader {
     pub dbg_info_header: Option<DebugInfoHeader>,
 }
 
+/// Flags of a prototype header, as set by the LuaJIT parser.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProtoFlags {
+    pub has_child: bool, //creates closures with FNEW.
+    pub is_vararg: bool, //takes ... after its fixed parameters.
+    pub uses_ffi: bool,  //has FFI cdata constants.
+    pub no_jit: bool,    //excluded from JIT compilation.
+    pub has_iloop: bool, //has loops patched into their interpreter-only variant.
+}
+
+impl ProtoFlags {
+    const CHILD: u8 = 0x01;
+    const VARARG: u8 = 0x02;
+    const FFI: u8 = 0x04;
+    const NOJIT: u8 = 0x08;
+    const ILOOP: u8 = 0x10;
+
+    pub fn new(flags: u8) -> ProtoFlags {
+        ProtoFlags {
+            has_child: flags & ProtoFlags::CHILD != 0,
+            is_vararg: flags & ProtoFlags::VARARG != 0,
+            uses_ffi: flags & ProtoFlags::FFI != 0,
+            no_jit: flags & ProtoFlags::NOJIT != 0,
+            has_iloop: flags & ProtoFlags::ILOOP != 0,
+        }
+    }
+}
+
 pub struct DebugInfoHeader {
     pub size_dbg: u32,
     pub first_line: u32,
     pub num_lines: u32,
 }
 
+pub struct VarInfo {
+    pub name: String,
+    pub start_pc: u32, //pc 0 is the function header, so instruction i is at pc i+1.
+    pub end_pc: u32,   //exclusive.
+}
+
+impl VarInfo {
+    const VARNAME_END: u8 = 0;
+    const VARNAME_MAX: u8 = 7;
+    const INTERNAL_NAMES: [&'static str; 7] = [
+        "",
+        "(for index)",
+        "(for limit)",
+        "(for step)",
+        "(for generator)",
+        "(for state)",
+        "(for control)",
+    ];
+
+    /// True when the variable is in scope at the given instruction index.
+    pub fn is_active(&self, index: usize) -> bool {
+        let pc = index as u32 + 1;
+        self.start_pc <= pc && pc < self.end_pc
+    }
+
+    /// Compiler generated variables such as the hidden slots of for loops.
+    pub fn is_internal(&self) -> bool {
+        self.name.starts_with('(')
+    }
+}
+
 pub struct UpValue {
     pub table_index: u8,
     pub table_location: u8,
@@ -38,6 +99,31 @@ impl UpValue { const UPVALUE_SIZE: u8 = 2; }
 pub struct Constants {
     pub strings: VecDeque<String>, 
     pub non_strings: Vec<LuaValue>,
+    pub kgcs: Vec<LuaValue>, //every kgc in file order. Operands index them from the end.
+    pub kns: Vec<LuaValue>,  //number constants in file order.
+}
+
+impl Constants {
+    ///! Returns the kgc an operand refers to. KGC operands count backwards from the last kgc.
+    pub fn kgc(&self, index: u16) -> Option<&LuaValue> {
+        let index = index as usize;
+        if index < self.kgcs.len() {
+            self.kgcs.get(self.kgcs.len() - 1 - index)
+        } else { None }
+    }
+
+    ///! Returns the string constant an operand refers to.
+    pub fn string(&self, index: u16) -> Option<&str> {
+        match self.kgc(index) {
+            Some(LuaValue::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    ///! Returns the number constant an operand refers to.
+    pub fn kn(&self, index: u16) -> Option<&LuaValue> {
+        self.kns.get(index as usize)
+    }
 }
 
 pub struct LuajitFileHeader {
@@ -57,8 +143,11 @@ pub struct Prototype {
     pub uvs: Vec<UpValue>,
     pub constants: Constants,
     pub symbols: Vec<String>,
+    pub uv_names: Vec<String>,
+    pub var_info: Vec<VarInfo>,
     pub instructions: Vec<Bci>,
     pub proto_children: Vec<usize>,
+    pub faults: Vec<String>, //operands the verifier found out of range, empty for well formed bytecode.
 }
 
 impl Prototype {
@@ -70,11 +159,19 @@ impl Prototype {
         let uvs = Prototype::read_raw_upvalues(&mut ljr, &header);
         let mut kgcs = Prototype::read_kgcs(&mut ljr, &header);
         let kns = Prototype::read_kns(&mut ljr, &header);
-        let (_symbols, _line_info) = Prototype::read_debug_lines_and_symbols(&mut ljr, &header);
+        let faults = Verifier::verify(&header, &bcis, &kgcs);
+        let (_line_info, uv_names, mut var_info) = Prototype::read_debug_info(&mut ljr, &header);
+        let _symbols = if var_info.is_empty() {
+            Prototype::generate_symbols(&header)
+        } else {
+            var_info.iter().map(|v| v.name.clone()).collect()
+        };
 
         let mut constants = Constants {
             strings: VecDeque::new(),
             non_strings: vec![],
+            kgcs: kgcs.clone(),
+            kns: kns.clone(),
         };
         let mut child_protos: Vec<usize> = vec![];
 
@@ -95,21 +192,66 @@ impl Prototype {
             ptr.next_id += 1;
         }
 
-        let marks = Prototype::get_marked_instructions(&mut bcis);
-        Prototype::mark_unexpected_jmps_as_goto_or_iterj(&mut bcis, marks);
+        //The passes over the instructions trust their operands, malformed bytecode is left as read.
+        if faults.is_empty() {
+            Prototype::normalize_patched_ops(&mut bcis);
+            if ptr.deobfuscate {
+                Deobfuscator::clean(&mut bcis, &mut var_info);
+            }
+            let marks = Prototype::get_marked_instructions(&bcis);
+            Prototype::mark_unexpected_jmps_as_goto_or_iterj(&mut bcis, marks);
+        }
 
         Prototype {
             header: header,
             uvs: uvs,
             constants: constants,
             symbols: _symbols,
+            uv_names: uv_names,
+            var_info: var_info,
             instructions: bcis,
             proto_children: child_protos,
+            faults: faults,
+        }
+    }
+
+    ///! Maps the interpreter and JIT variants of the loop and function header ops back to their canonical op and marks them as patched.
+    ///! JFORL, JITERL and JLOOP hold a trace number in D, their jump target is recovered from the loop they belong to.
+    fn normalize_patched_ops(bcis: &mut [Bci]) {
+        for i in 0..bcis.len() {
+            let op = match bcis[i].op {
+                74      => 73, //JFORI
+                76 | 77 => 75, //IFORL, JFORL
+                79 | 80 => 78, //IITERL, JITERL
+                82 | 83 => 81, //ILOOP, JLOOP
+                86 | 87 => 85, //IFUNCF, JFUNCF
+                89 | 90 => 88, //IFUNCV, JFUNCV
+                _       => continue,
+            };
+            let jumps_to = |b: &Bci, target: usize| b.is_jump() && b.get_jump_target() as usize == target;
+            let target = match bcis[i].op {
+                //FORL jumps back to the start of the body, right after the FORI that exits past the FORL.
+                77 => bcis[..i].iter().position(|b| b.op == 73 && jumps_to(b, i + 1)).map(|f| f + 1),
+                //ITERL jumps back to the start of the body, right after the JMP or ISNEXT to its ITERC.
+                80 => bcis[..i].iter().position(|b| i > 0 && jumps_to(b, i - 1)).map(|j| j + 1),
+                //LOOP exits past the back edge of its loop, the first jump after it that targets the LOOP or before.
+                83 => (i + 1..bcis.len())
+                    .find(|&j| bcis[j].is_jump() && (bcis[j].get_jump_target() as usize) <= i)
+                    .map(|j| j + 1),
+                _  => None,
+            };
+            let bci = &mut bcis[i];
+            if matches!(bci.op, 77 | 80 | 83) {
+                //Without the loop there is nothing to jump to, falling through keeps the instruction harmless.
+                bci.set_jump_target(target.unwrap_or(i + 1));
+            }
+            bci.op = op;
+            bci.patched = true;
         }
     }
 
     ///! Returns bytecode instructions that are marked as either Unexpected, Expeceted, or IterJ.
-    fn get_marked_instructions(bcis: &Vec<Bci>) -> Vec<Mark> {
+    fn get_marked_instructions(bcis: &[Bci]) -> Vec<Mark> {
         //bci[i+1] is an expected jmp.
         //bci[bci[i+1].target - 1] is an expected jmp. (aka the target of the first expected jmp - 1)
         //Any unexpected JMP/UCLO is a goto.
@@ -134,15 +276,16 @@ impl Prototype {
         marks
     }
 
-    ///! Changes bytecode instruction opcodes which are marked as Unexpected or IterJ that are also either JMP or UCLO instructions.
+    ///! Changes bytecode instruction opcodes which are marked as Unexpected or IterJ that are also JMP instructions.
+    ///! UCLO keeps its opcode, the slot it closes upvalues from delimits the scope it ends.
     fn mark_unexpected_jmps_as_goto_or_iterj(bcis: &mut Vec<Bci>, marks: Vec<Mark>) {
         for (i, m) in marks.iter().enumerate() {
-            let is_jmp_or_uclo = bcis[i].op == 84 || bcis[i].op == 48;
+            let is_jmp = bcis[i].op == 84;
             match *m {
                 //Make unexpected JMP into a GOTO.
-                Mark::Unexpected    if is_jmp_or_uclo => bcis[i].op = 93,
+                Mark::Unexpected    if is_jmp => bcis[i].op = 93,
                 //Make JMP into IterJ.
-                Mark::IterJ         if is_jmp_or_uclo => bcis[i].op = 94,
+                Mark::IterJ         if is_jmp => bcis[i].op = 94,
                 //Expected or conditional JMP instructions don't need changed.
                 Mark::Expected                     => (),
                 //Do nothing for the rest of the Unexpected instructions because otherwise, LOOP/FOR/FORI/etc... would be effected.
@@ -151,10 +294,11 @@ impl Prototype {
         }
     }
 
-    ///! Read debug information from the prototype. These are the variable names.
-    fn read_debug_lines_and_symbols(ljr: &mut LJReader, header: &PrototypeHeader) -> (Vec<String>, Vec<u8>) {
-        let mut symbols: Vec<String> = vec![];
+    ///! Read debug information from the prototype. These are the line numbers, upvalue names and variable names with their scopes.
+    fn read_debug_info(ljr: &mut LJReader, header: &PrototypeHeader) -> (Vec<u8>, Vec<String>, Vec<VarInfo>) {
         let mut line_nums: Vec<u8> = vec![];
+        let mut uv_names: Vec<String> = vec![];
+        let mut var_info: Vec<VarInfo> = vec![];
 
         if let Some(dih) = &header.dbg_info_header {
             let dbg_info: Vec<u8> = ljr.read_bytes(dih.size_dbg as usize);
@@ -162,36 +306,47 @@ impl Prototype {
             line_nums = Prototype::read_line_num_section(header, dih, &dbg_info, &mut offset);
 
             if offset < dbg_info.len() {
-                symbols = Prototype::extract_symbols(&dbg_info, &mut offset);
-
-            } else { symbols = Prototype::generate_symbols(header); }
-
-        } else {
-            symbols = Prototype::generate_symbols(header);
+                for _ in 0..header.size_uv {
+                    uv_names.push(Prototype::extract_symbol(&dbg_info, &mut offset));
+                }
+                var_info = Prototype::extract_var_info(&dbg_info, &mut offset);
+            }
         }
-        (symbols, line_nums)
+        (line_nums, uv_names, var_info)
     }
 
     ///! Read the debug line numbers. This contains information of which bytecode instructions belong on which line. 1:1 correspondence with BCIs.
     fn read_line_num_section(header: &PrototypeHeader, dih: &DebugInfoHeader, dbg_info: &Vec<u8>, offset: &mut usize) -> Vec<u8> {
         let entry_size = Prototype::line_entry_size(dih.num_lines);
-        let line_sec_size = 1 + (entry_size * (header.instruction_count - 1)) as usize;
+        let line_sec_size = (entry_size * header.instruction_count) as usize;
         *offset += line_sec_size;
         dbg_info[0..line_sec_size].to_vec()
     }
 
-    ///! Extracts symbols (variable names) from its section after the line number section.
-    fn extract_symbols(dbg_info: &Vec<u8>, offset: &mut usize) -> Vec<String> {
-        let mut symbols: Vec<String> = vec![];
-        loop {
-            if *offset >= dbg_info.len() - 1 { break; } // +1 since this section terminates in 0x00.
-            let sym = Prototype::extract_symbol(&dbg_info, offset);
-            symbols.push(sym);
+    ///! Extracts variable names and the range of pcs they are in scope for. The section terminates in 0x00.
+    fn extract_var_info(dbg_info: &Vec<u8>, offset: &mut usize) -> Vec<VarInfo> {
+        let mut vars: Vec<VarInfo> = vec![];
+        let mut last_pc = 0;
+        while *offset < dbg_info.len() && dbg_info[*offset] != VarInfo::VARNAME_END {
+            let name = if dbg_info[*offset] < VarInfo::VARNAME_MAX {
+                *offset += 1;
+                VarInfo::INTERNAL_NAMES[dbg_info[*offset - 1] as usize].to_string()
+            } else {
+                Prototype::extract_symbol(dbg_info, offset)
+            };
+            let start_pc = last_pc + Prototype::read_uleb_at(dbg_info, offset);
+            let end_pc = start_pc + Prototype::read_uleb_at(dbg_info, offset);
+            last_pc = start_pc;
+            vars.push(VarInfo {
+                name: name,
+                start_pc: start_pc,
+                end_pc: end_pc,
+            });
         }
-        symbols
+        vars
     }
 
-    ///! Extract an individual symbol at the given offset.
+    ///! Extract an individual null terminated symbol at the given offset.
     fn extract_symbol(dbg_info: &Vec<u8>, offset: &mut usize) -> String {
         let mut utf8: Vec<u8> = vec![];
         loop {
@@ -199,10 +354,60 @@ impl Prototype {
             utf8.push(dbg_info[*offset]);
             *offset += 1;
         }
-        *offset += 3; //skip null terminator + 2 unknown bytes. Unknown bytes *could* be 2 ulebs...not 100% sure. -> lj_debug.c/ line:172 -> line:176
+        *offset += 1; //skip null terminator.
         String::from_utf8(utf8).expect("Failed to convert symbol to utf8.")
     }
 
+    ///! Reads a uleb128 from the debug info section.
+    fn read_uleb_at(dbg_info: &Vec<u8>, offset: &mut usize) -> u32 {
+        let mut value: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = dbg_info[*offset];
+            *offset += 1;
+            value |= ((byte & 127) as u32) << shift;
+            shift += 7;
+            if byte & 128 == 0 { break; }
+        }
+        value
+    }
+
+    ///! Name of the variable in slot at the given instruction index, if the prototype has debug info for it.
+    pub fn slot_name(&self, slot: u16, index: usize) -> Option<&str> {
+        let mut active = self.var_info.iter().filter(|v| v.is_active(index));
+        active.nth(slot as usize).map(|v| v.name.as_str())
+    }
+
+    ///! Names of the parameters, if the prototype has debug info for them.
+    pub fn param_names(&self) -> Vec<&str> {
+        self.var_info.iter()
+            .take(self.header.num_params as usize)
+            .filter(|v| v.start_pc == 0)
+            .map(|v| v.name.as_str())
+            .collect()
+    }
+
+    ///! Id of the child prototype a FNEW operand refers to. Children are listed in the order of their kgcs.
+    pub fn child_id(&self, index: u16) -> Option<usize> {
+        if !self.header.flags.has_child { return None; }
+        let kgcs = &self.constants.kgcs;
+        let position = kgcs.len().checked_sub(1 + index as usize)?;
+        if kgcs[position] != LuaValue::ChildProto { return None; }
+        let nth = kgcs[..position].iter().filter(|k| **k == LuaValue::ChildProto).count();
+        self.proto_children.get(nth).cloned()
+    }
+
+    ///! Variables that come into scope at the given instruction index, paired with the slot they occupy.
+    pub fn vars_starting_at(&self, index: usize) -> Vec<(u16, &VarInfo)> {
+        let pc = index as u32 + 1;
+        self.var_info.iter()
+            .filter(|v| v.is_active(index))
+            .enumerate()
+            .filter(|(_, v)| v.start_pc == pc)
+            .map(|(slot, v)| (slot as u16, v))
+            .collect()
+    }
+
     ///! Determine the size of the entries, in number of bytes, in the line number section,
     fn line_entry_size(num_lines: u32) -> u32 {
         match num_lines {
@@ -290,7 +495,7 @@ impl Prototype {
     fn read_header(ljr: &mut LJReader, ptr: &Prototyper) -> PrototypeHeader {
         let mut pth = PrototypeHeader {
             id: ptr.next_id,
-            flags: ljr.read_byte(),
+            flags: ProtoFlags::new(ljr.read_byte()),
             num_params: ljr.read_byte(),
             frame_size: ljr.read_byte(),
             size_uv: ljr.read_byte(),
@@ -324,6 +529,7 @@ pub struct Prototyper {
     reader: LJFileReader,
     ljfh: LuajitFileHeader,
     proto_id_stack: Vec<usize>,
+    pub deobfuscate: bool, //clean up junk jumps and opaque predicates before the jumps are marked.
 }
 
 impl Prototyper {
@@ -353,6 +559,7 @@ impl Prototyper {
             reader: reader,
             ljfh: ljfh,
             proto_id_stack: vec![],
+            deobfuscate: false,
         }
     }
 
@@ -385,7 +592,7 @@ mod tests {
 
         //header checking
         assert!(pt.header.id == 0);
-        assert!(pt.header.flags == 2);
+        assert!(pt.header.flags == ProtoFlags{is_vararg: true, ..ProtoFlags::default()});
         assert!(pt.header.num_params == 0);
         assert!(pt.header.frame_size == 2);
         assert!(pt.header.size_uv == 0);
@@ -396,5 +603,68 @@ mod tests {
 
         //prototype checking
         assert!(pt.constants.strings[0] == "print");
+        assert!(pt.constants.string(0) == Some("print"));
+        assert!(pt.constants.kgc(1).is_none());
+        assert!(pt.var_info.is_empty());
+        assert!(pt.symbols == vec!["var_pt0_0", "var_pt0_1"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_var_info() {
+        let mut ptr = Prototyper::new("dec.lua");
+        ptr.next().unwrap(); //dec.ifs
+        let pt = ptr.next().unwrap(); //dec.loops
+        assert!(pt.var_info[0].name == "x");
+        assert!(pt.var_info[1].name == "y");
+        assert!(pt.symbols.iter().any(|s| s == "(for index)"));
+        assert!(pt.symbols.iter().all(|s| !s.is_empty()));
+
+        //x is declared by the first instruction and stays in scope until the end of the function.
+        assert!(pt.var_info[0].start_pc == 2);
+        assert!(pt.slot_name(0, 1) == Some("x"));
+        assert!(pt.vars_starting_at(1).iter().any(|(slot, v)| *slot == 0 && v.name == "x"));
+    }
+
+    #[test]
+    fn test_proto_flags() {
+        let flags = ProtoFlags::new(0x1f);
+        assert!(flags.has_child && flags.is_vararg && flags.uses_ffi && flags.no_jit && flags.has_iloop);
+        assert!(ProtoFlags::new(0x01) == ProtoFlags{has_child: true, ..ProtoFlags::default()});
+
+        //the main chunk of dec.lua creates its functions and takes the script arguments.
+        let mut ptr = Prototyper::new("dec.lua");
+        let mut main = ptr.next().unwrap();
+        while let Some(pt) = ptr.next() { main = pt; }
+        assert!(main.header.flags.has_child && main.header.flags.is_vararg);
+    }
+
+    #[test]
+    fn test_normalize_patched_ops() {
+        let jump = |index, op, target: usize| {
+            let d = (target as i64 - 1 - index as i64 + 0x8000) as u16;
+            Bci::new(index, op, 0, d as u8, (d >> 8) as u8)
+        };
+        let mut bcis = vec![
+            jump(0, 74, 3),           //JFORI
+            Bci::new(1, 16, 4, 0, 0), //MOV
+            Bci::new(2, 77, 0, 7, 0), //JFORL, trace 7
+            Bci::new(3, 83, 0, 8, 0), //JLOOP, trace 8
+            jump(4, 84, 3),           //JMP
+            jump(5, 84, 7),           //JMP to the ITERC
+            Bci::new(6, 16, 4, 0, 0), //MOV
+            Bci::new(7, 65, 3, 3, 2), //ITERC
+            Bci::new(8, 80, 3, 9, 0), //JITERL, trace 9
+            Bci::new(9, 87, 2, 0, 0), //JFUNCF
+        ];
+        Prototype::normalize_patched_ops(&mut bcis);
+
+        let ops: Vec<u8> = bcis.iter().map(|b| b.op).collect();
+        assert!(ops == vec![73, 16, 75, 81, 84, 84, 16, 65, 78, 85]);
+        assert!(bcis.iter().filter(|b| b.patched).count() == 5);
+        assert!(bcis[0].get_jump_target() == 3);
+        assert!(bcis[2].get_jump_target() == 1);
+        assert!(bcis[3].get_jump_target() == 5);
+        assert!(bcis[8].get_jump_target() == 6);
+        assert!(bcis[9].a() == 2);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::dis::{
+    bytecode_instruction::Bci,
+    lua_table::LuaValue,
+    prototyper::PrototypeHeader,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    None,
+    Var,   //stack slot, read or written.
+    RBase, //first free slot, may equal the frame size.
+    Lit,   //literal, any value is valid.
+    Pri,   //nil, false or true.
+    Str,   //string kgc.
+    Num,   //number kn.
+    Uv,    //upvalue.
+    Func,  //child prototype kgc.
+    Tab,   //template table kgc.
+    CData, //cdata kgc.
+    Jump,  //jump target.
+}
+
+pub struct Verifier{}
+impl Verifier {
+    ///! Checks every instruction's operands against the sizes in the prototype header, before any pass relies on them.
+    ///! Returns a description of each fault found, empty for well formed bytecode.
+    pub fn verify(header: &PrototypeHeader, bcis: &[Bci], kgcs: &[LuaValue]) -> Vec<String> {
+        let mut faults: Vec<String> = vec![];
+        for bci in bcis.iter() {
+            let fault = |message: String| format!("{:>4}: {} {}", bci.index, bci.get_operation_name(), message);
+            if bci.op > 92 {
+                faults.push(format!("{:>4}: unknown opcode {}", bci.index, bci.op));
+                continue;
+            }
+
+            let (a, b, cd) = Verifier::operands(bci.op);
+            let checks = if b == Operand::None {
+                vec![(a, bci.a() as u16, "A"), (cd, bci.d(), "D")]
+            } else {
+                vec![(a, bci.a() as u16, "A"), (b, bci.b() as u16, "B"), (cd, bci.c() as u16, "C")]
+            };
+            for (operand, value, register) in checks {
+                if let Some(message) = Verifier::check(operand, value, bci, header, bcis.len(), kgcs) {
+                    faults.push(fault(format!("{}: {}", register, message)));
+                }
+            }
+
+            //A comparison only sets the condition, the JMP after it does the jump.
+            if bci.op < 16 && bcis.get(bci.index + 1).is_none_or(|next| next.op != 84) {
+                faults.push(fault("is not followed by a JMP".to_string()));
+            }
+        }
+
+        if !bcis.last().is_some_and(|bci| (69..=72).contains(&bci.op)) {
+            faults.push("missing trailing RET".to_string());
+        }
+        faults
+    }
+
+    fn check(operand: Operand, value: u16, bci: &Bci, header: &PrototypeHeader, len: usize, kgcs: &[LuaValue]) -> Option<String> {
+        //Kgc operands count backwards from the last kgc.
+        let kgc = kgcs.len().checked_sub(1 + value as usize).map(|i| &kgcs[i]);
+        let frame_size = header.frame_size as u16;
+        match operand {
+            Operand::Var if value >= frame_size => Some(format!("slot {} is outside the frame of {}", value, frame_size)),
+            Operand::RBase if value > frame_size => Some(format!("base {} is outside the frame of {}", value, frame_size)),
+            Operand::Pri if value > 2 => Some(format!("{} is not a primitive", value)),
+            Operand::Num if value as u32 >= header.size_kn => Some(format!("kn {} is outside the {} number constants", value, header.size_kn)),
+            Operand::Uv if value >= header.size_uv as u16 => Some(format!("upvalue {} is outside the {} upvalues", value, header.size_uv)),
+            Operand::Str | Operand::Func | Operand::Tab | Operand::CData if kgc.is_none() =>
+                Some(format!("kgc {} is outside the {} gc constants", value, header.size_kgc)),
+            Operand::Str if !matches!(kgc, Some(LuaValue::Str(_))) => Some(format!("kgc {} is not a string", value)),
+            Operand::Func if kgc != Some(&LuaValue::ChildProto) => Some(format!("kgc {} is not a child prototype", value)),
+            Operand::Tab if !matches!(kgc, Some(LuaValue::Table(_))) => Some(format!("kgc {} is not a table", value)),
+            Operand::CData if !matches!(kgc, Some(LuaValue::I64(_) | LuaValue::U64(_) | LuaValue::ComplexNum(_))) =>
+                Some(format!("kgc {} is not cdata", value)),
+            Operand::Jump => {
+                //JFORL, JITERL and JLOOP hold a trace number instead, they are not jumps until normalized.
+                let target = 1 + bci.index as i64 + bci.jump_offset() as i64;
+                if bci.is_jump() && (target < 0 || target >= len as i64) {
+                    Some(format!("jump target {} is outside the {} instructions", target, len))
+                } else { None }
+            },
+            _ => None,
+        }
+    }
+
+    ///! Operand kinds of A, B and C, or of A and D when B is None.
+    fn operands(op: u8) -> (Operand, Operand, Operand) {
+        use Operand::*;
+        match op {
+            0..=5   => (Var, None, Var),  //ISLT..ISNEV
+            6 | 7   => (Var, None, Str),  //ISEQS, ISNES
+            8 | 9   => (Var, None, Num),  //ISEQN, ISNEN
+            10 | 11 => (Var, None, Pri),  //ISEQP, ISNEP
+            12 | 13 => (Var, None, Var),  //ISTC, ISFC
+            14 | 15 => (None, None, Var), //IST, ISF
+            16..=19 => (Var, None, Var),  //MOV, NOT, UNM, LEN
+            20..=29 => (Var, Var, Num),   //arithmetic with a number constant
+            30..=36 => (Var, Var, Var),   //arithmetic on slots, POW, CAT
+            37      => (Var, None, Str),  //KSTR
+            38      => (Var, None, CData),//KCDATA
+            39      => (Var, None, Lit),  //KSHORT
+            40      => (Var, None, Num),  //KNUM
+            41      => (Var, None, Pri),  //KPRI
+            42      => (Var, None, Var),  //KNIL
+            43      => (Var, None, Uv),   //UGET
+            44      => (Uv, None, Var),   //USETV
+            45      => (Uv, None, Str),   //USETS
+            46      => (Uv, None, Num),   //USETN
+            47      => (Uv, None, Pri),   //USETP
+            48      => (RBase, None, Jump), //UCLO
+            49      => (Var, None, Func), //FNEW
+            50      => (Var, None, Lit),  //TNEW
+            51      => (Var, None, Tab),  //TDUP
+            52 | 53 => (Var, None, Str),  //GGET, GSET
+            54 | 57 => (Var, Var, Var),   //TGETV, TSETV
+            55 | 58 => (Var, Var, Str),   //TGETS, TSETS
+            56 | 59 => (Var, Var, Lit),   //TGETB, TSETB
+            60      => (Var, None, Num),  //TSETM
+            61 | 62 | 65..=67 => (Var, Lit, Lit), //CALLM, CALL, ITERC, ITERN, VARG
+            63 | 64 => (Var, None, Lit),  //CALLMT, CALLT
+            68      => (Var, None, Jump), //ISNEXT
+            69..=72 => (RBase, None, Lit),//RETM, RET, RET0, RET1
+            73..=84 => (RBase, None, Jump), //FOR, ITERL, LOOP and their variants, JMP
+            _       => (RBase, None, Lit),//function headers
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dis::prototyper::Prototyper;
+
+    #[test]
+    fn test_verify_well_formed() {
+        for file in ["dec.lua", "singleif.ljc", "beam_system_client.lua"] {
+            let mut ptr = Prototyper::new(file);
+            while let Some(pt) = ptr.next() {
+                assert!(pt.faults.is_empty(), "{} {}: {:?}", file, pt.header.id, pt.faults);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_malformed() {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let jump = |index, target: i64| {
+            let d = (target - 1 - index as i64 + 0x8000) as u16;
+            Bci::new(index, 84, 0, d as u8, (d >> 8) as u8)
+        };
+        let bcis = vec![
+            Bci::new(0, 16, 9, 0, 0), //MOV to a slot outside the frame of 2
+            Bci::new(1, 52, 0, 5, 0), //GGET of a kgc that does not exist
+            Bci::new(2, 43, 0, 0, 0), //UGET without upvalues
+            Bci::new(3, 1, 0, 1, 0),  //ISGE without its JMP
+            Bci::new(4, 95, 0, 0, 0),
+            jump(5, 40),
+        ];
+        let faults = Verifier::verify(&pt.header, &bcis, &pt.constants.kgcs);
+        assert!(faults.len() == 7, "{:?}", faults);
+        assert!(faults[0].contains("MOV A: slot 9"));
+        assert!(faults[1].contains("GGET D: kgc 5"));
+        assert!(faults[2].contains("UGET D: upvalue 0"));
+        assert!(faults[3].contains("ISGE is not followed by a JMP"));
+        assert!(faults[4].contains("unknown opcode 95"));
+        assert!(faults[5].contains("jump target 40"));
+        assert!(faults[6] == "missing trailing RET");
+    }
+}
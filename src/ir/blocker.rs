@@ -73,11 +73,27 @@ impl Blocker {
         let mut targets: BTreeSet<usize> = BTreeSet::new();
         targets.insert(0);
         for i in jump_indices.iter() {
-            if *i < 0 {
-                targets.insert(2 + (-*i) as usize);
-            } else {
-                let jmp = &pt.instructions[*i as usize];
+            //-0 is 0, so the sign alone cannot tell a comparison at index 0 from a jump.
+            let index = i.unsigned_abs();
+            let jmp = &pt.instructions[index];
+            if jmp.is_jump() {
                 targets.insert(jmp.get_jump_target() as usize);
+            } else {
+                targets.insert(2 + index);
+            }
+        }
+        for (i, bci) in pt.instructions.iter().enumerate() {
+            //Anything after an unconditional transfer of control starts a new block.
+            let after_comparison = i > 0 && pt.instructions[i - 1].op < 16;
+            let is_unconditional = match bci.op {
+                48 | 84 => !after_comparison, //UCLO, JMP
+                63 | 64 | 68..=72 | 93 | 94 => true, //CALLMT, CALLT, ISNEXT, RET*, GOTO, ITERJ
+                _ => false,
+            };
+            //FORL and ITERL loop back or fall out of the loop, so the loop exit starts a block too.
+            let is_loop_back = (75..=79).contains(&bci.op);
+            if (is_unconditional || is_loop_back) && i + 1 < pt.instructions.len() {
+                targets.insert(i + 1);
             }
         }
         targets
@@ -152,6 +168,17 @@ mod tests {
         assert!(blocks[4].instructions[..] == pt.instructions[21..]);
     }
 
+    #[test]
+    fn test_make_blocks_comparison_at_start() {
+        //Several beam prototypes start with ISTC/ISFC at index 0.
+        let mut ptr = Prototyper::new("beam_system_client.lua");
+        while let Some(pt) = ptr.next() {
+            let blocks = Blocker{}.make_blocks(&pt);
+            let count: usize = blocks.iter().map(|b| b.instructions.len()).sum();
+            assert!(count == pt.instructions.len());
+        }
+    }
+
     #[test]
     fn debug_write_blocks() {
         let mut ptr = Prototyper::new("dec.lua");
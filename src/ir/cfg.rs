@@ -0,0 +1,212 @@
+// Control flow graph over the basic blocks of a prototype.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::dis::bytecode_instruction::Bci;
+use crate::ir::blocker::Block;
+
+pub struct Cfg {
+    pub succs: Vec<Vec<usize>>, //block id -> successor block ids. Conditional blocks list the taken edge first.
+    pub preds: Vec<Vec<usize>>, //block id -> predecessor block ids.
+}
+
+impl Cfg {
+    /// Builds the edges between blocks from the last instruction of every block.
+    pub fn new(blocks: &[Block]) -> Cfg {
+        let starts: HashMap<usize, usize> = blocks.iter().map(|b| (b.start_index, b.id)).collect();
+        let mut succs: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+        let mut preds: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+
+        for block in blocks.iter() {
+            let next = if block.id + 1 < blocks.len() { Some(block.id + 1) } else { None };
+            let target = |bci: &Bci| starts.get(&(bci.get_jump_target() as usize)).cloned();
+            let last = match block.instructions.last() {
+                Some(last) => last,
+                None => continue,
+            };
+
+            let mut edges: Vec<Option<usize>> = vec![];
+            match last.op {
+                //JMP, GOTO, ITERJ, UCLO. Conditional when a comparison skips over it.
+                84 | 93 | 94 | 48 => {
+                    edges.push(target(last));
+                    if Cfg::is_conditional_jump(block) {
+                        edges.push(next);
+                    }
+                },
+                68 => edges.push(target(last)), //ISNEXT
+                73 | 74 => { edges.push(target(last)); edges.push(next); }, //FORI, JFORI: exit or enter.
+                75 | 76 | 78 | 79 => { edges.push(target(last)); edges.push(next); }, //FORL, IFORL, ITERL, IITERL: loop back or exit.
+                63 | 64 | 69..=72 => (), //CALLMT, CALLT, RET*
+                _ => edges.push(next), //LOOP and everything else falls through.
+            }
+
+            for succ in edges.into_iter().flatten() {
+                if !succs[block.id].contains(&succ) {
+                    succs[block.id].push(succ);
+                    preds[succ].push(block.id);
+                }
+            }
+        }
+
+        Cfg {
+            succs: succs,
+            preds: preds,
+        }
+    }
+
+    /// A jump is conditional when it directly follows a comparison or unary test.
+    fn is_conditional_jump(block: &Block) -> bool {
+        let len = block.instructions.len();
+        len > 1 && block.instructions[len - 2].op < 16
+    }
+
+    pub fn len(&self) -> usize {
+        self.succs.len()
+    }
+
+    /// Block ids in reverse post order starting from the entry block. Unreachable blocks are not included.
+    pub fn reverse_post_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = vec![];
+        if self.len() == 0 { return order; }
+
+        let mut visited = vec![false; self.len()];
+        let mut stack: Vec<(usize, usize)> = vec![(0, 0)]; //block, next successor to visit.
+        visited[0] = true;
+        while let Some((block, i)) = stack.pop() {
+            if i < self.succs[block].len() {
+                stack.push((block, i + 1));
+                let succ = self.succs[block][i];
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Immediate dominator of every block. The entry block dominates itself and unreachable blocks have none.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        let order = self.reverse_post_order();
+        let mut rpo_index = vec![usize::MAX; self.len()];
+        for (i, b) in order.iter().enumerate() {
+            rpo_index[*b] = i;
+        }
+
+        let mut idom: Vec<Option<usize>> = vec![None; self.len()];
+        if order.is_empty() { return idom; }
+        idom[0] = Some(0);
+
+        let intersect = |idom: &Vec<Option<usize>>, mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_index[a] > rpo_index[b] { a = idom[a].unwrap(); }
+                while rpo_index[b] > rpo_index[a] { b = idom[b].unwrap(); }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in order.iter().skip(1).cloned() {
+                let mut new_idom: Option<usize> = None;
+                for p in self.preds[b].iter().cloned() {
+                    if idom[p].is_none() { continue; }
+                    new_idom = match new_idom {
+                        None => Some(p),
+                        Some(d) => Some(intersect(&idom, p, d)),
+                    };
+                }
+                if new_idom != idom[b] {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    /// Dominance frontier of every block given the immediate dominators.
+    pub fn dominance_frontiers(&self, idom: &[Option<usize>]) -> Vec<BTreeSet<usize>> {
+        let mut frontiers: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); self.len()];
+        for b in 0..self.len() {
+            if self.preds[b].len() < 2 || idom[b].is_none() { continue; }
+            for p in self.preds[b].iter().cloned() {
+                let mut runner = p;
+                while idom[runner].is_some() && Some(runner) != idom[b] {
+                    frontiers[runner].insert(b);
+                    if runner == 0 { break; }
+                    runner = idom[runner].unwrap();
+                }
+            }
+        }
+        frontiers
+    }
+
+    /// True when block a dominates block b.
+    pub fn dominates(idom: &[Option<usize>], a: usize, b: usize) -> bool {
+        let mut runner = b;
+        loop {
+            if runner == a { return true; }
+            match idom[runner] {
+                Some(d) if d != runner => runner = d,
+                _ => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::blocker::Blocker;
+    use super::*;
+
+    #[test]
+    fn test_new_cfg() {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        assert!(cfg.len() == 5);
+        //Every if jumps past the remaining ifs to the RET0 when its condition fails.
+        assert!(cfg.succs[0] == vec![4, 1], "found: {:?}", cfg.succs[0]);
+        assert!(cfg.succs[1] == vec![4, 2], "found: {:?}", cfg.succs[1]);
+        assert!(cfg.succs[2] == vec![4, 3], "found: {:?}", cfg.succs[2]);
+        assert!(cfg.succs[3] == vec![4], "found: {:?}", cfg.succs[3]);
+        assert!(cfg.succs[4].is_empty());
+        assert!(cfg.preds[4] == vec![0, 1, 2, 3], "found: {:?}", cfg.preds[4]);
+    }
+
+    #[test]
+    fn test_reverse_post_order() {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let cfg = Cfg::new(&Blocker{}.make_blocks(&pt));
+        let rpo = cfg.reverse_post_order();
+        assert!(rpo.len() == 5);
+        assert!(rpo[0] == 0);
+        assert!(rpo[4] == 4);
+    }
+
+    #[test]
+    fn test_dominators() {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let cfg = Cfg::new(&Blocker{}.make_blocks(&pt));
+        let idom = cfg.dominators();
+        assert!(idom == vec![Some(0), Some(0), Some(1), Some(2), Some(0)], "found: {:?}", idom);
+        assert!(Cfg::dominates(&idom, 1, 3));
+        assert!(!Cfg::dominates(&idom, 3, 4));
+
+        let frontiers = cfg.dominance_frontiers(&idom);
+        assert!(frontiers[0].is_empty());
+        for b in 1..4 {
+            assert!(frontiers[b].contains(&4), "found: {:?}", frontiers[b]);
+        }
+    }
+}
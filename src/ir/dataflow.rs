@@ -0,0 +1,354 @@
+// Data-flow analyses over the IR of a prototype: liveness of slots, reaching definitions and def-use chains.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ir::{
+    cfg::Cfg,
+    ir_gen::Exp,
+    translator::IRPrototype,
+};
+
+/// Location of an expression: (block id, index of the expression in the block).
+pub type Loc = (usize, usize);
+
+/// A slot written by the expression at loc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Def {
+    pub loc: Loc,
+    pub slot: u16,
+}
+
+pub struct Slots{}
+impl Slots {
+    /// Slots written by an expression.
+    pub fn defs(exp: &Exp) -> Vec<u16> {
+        match exp {
+            Exp::Move(dst, _) if Slots::is_slot_target(dst) => Slots::range(dst),
+            Exp::Local(dst, _)          => Slots::range(dst),
+            Exp::Do(body)               => body.iter().flat_map(Slots::defs).collect(),
+            Exp::Call(_, _, returns)    => Slots::range(returns),
+            Exp::IsT(mv, _)             => Slots::defs(mv),
+            Exp::VarArg(range)          => Slots::range(range),
+            Exp::For(var, _, _, _, _)   => Slots::range(var), //FORI copies the index into the visible loop variable.
+            _ => vec![],
+        }
+    }
+
+    /// Slots read by an expression.
+    pub fn uses(exp: &Exp) -> Vec<u16> {
+        let mut slots: Vec<u16> = vec![];
+        Slots::reads(exp, &mut slots);
+        slots.sort_unstable();
+        slots.dedup();
+        slots
+    }
+
+    fn reads(exp: &Exp, slots: &mut Vec<u16>) {
+        match exp {
+            Exp::Var(v) | Exp::SVar(v, _) | Exp::Named(v, _) | Exp::MultRes(v) => slots.push(*v),
+            Exp::Move(dst, src) => {
+                if !Slots::is_slot_target(dst) {
+                    Slots::reads(dst, slots); //table or upvalue stores read their operands.
+                }
+                Slots::reads(src, slots);
+            },
+            Exp::Call(f, params, _) => {
+                Slots::reads(f, slots);
+                slots.extend(Slots::range(params));
+            },
+            Exp::Return(v) => match v.as_ref() {
+                Exp::Range(_, _) | Exp::List(_) => slots.extend(Slots::range(v)),
+                _ => Slots::reads(v, slots),
+            },
+            Exp::Local(_, src) => match src.as_ref() {
+                Exp::List(values) => values.iter().for_each(|v| Slots::reads(v, slots)),
+                _ => Slots::reads(src, slots),
+            },
+            Exp::Do(body) => {
+                //Only reads of values from before the block.
+                let mut written: Vec<u16> = vec![];
+                for exp in body.iter() {
+                    slots.extend(Slots::uses(exp).into_iter().filter(|s| !written.contains(s)));
+                    written.extend(Slots::defs(exp));
+                }
+            },
+            Exp::IsT(_, d) => Slots::reads(d, slots),
+            Exp::For(_, start, stop, step, _) => {
+                Slots::reads(start, slots);
+                Slots::reads(stop, slots);
+                Slots::reads(step, slots);
+            },
+            Exp::IterFor(_, explist, _) => Slots::reads(explist, slots),
+            Exp::VarArg(_) => (), //a range of slots written, not read.
+            _ => {
+                for child in exp.children() {
+                    Slots::reads(child, slots);
+                }
+            },
+        }
+    }
+
+    /// True when a Move into dst writes slots rather than a table or upvalue.
+    pub fn is_slot_target(dst: &Exp) -> bool {
+        matches!(dst, Exp::Var(_) | Exp::SVar(_, _) | Exp::Named(_, _) | Exp::Range(_, _) | Exp::List(_))
+    }
+
+    /// Slot of a single Var, or of a Named local.
+    pub fn slot(exp: &Exp) -> Option<u16> {
+        match exp {
+            Exp::Var(v) | Exp::Named(v, _) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Slots of a Range (inclusive, empty when inverted), a List of slots or a single Var or Named. MultRes stands for
+    /// an unknown number of slots and is approximated by its first one.
+    pub fn range(exp: &Exp) -> Vec<u16> {
+        match exp {
+            Exp::Var(v) | Exp::SVar(v, _) | Exp::Named(v, _) | Exp::MultRes(v) => vec![*v],
+            Exp::Range(from, to) => (*from..=*to).map(|s| s as u16).collect(),
+            Exp::List(exps) => exps.iter().flat_map(Slots::range).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+pub struct Liveness {
+    pub live_in: Vec<BTreeSet<u16>>,
+    pub live_out: Vec<BTreeSet<u16>>,
+}
+
+impl Liveness {
+    pub fn new(ir: &IRPrototype, cfg: &Cfg) -> Liveness {
+        let mut gen: Vec<BTreeSet<u16>> = vec![];
+        let mut kill: Vec<BTreeSet<u16>> = vec![];
+        for block in ir.iter() {
+            let mut g: BTreeSet<u16> = BTreeSet::new();
+            let mut k: BTreeSet<u16> = BTreeSet::new();
+            for exp in block.iter() {
+                for slot in Slots::uses(exp) {
+                    if !k.contains(&slot) { g.insert(slot); }
+                }
+                k.extend(Slots::defs(exp));
+            }
+            gen.push(g);
+            kill.push(k);
+        }
+
+        let mut live_in: Vec<BTreeSet<u16>> = vec![BTreeSet::new(); cfg.len()];
+        let mut live_out: Vec<BTreeSet<u16>> = vec![BTreeSet::new(); cfg.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..cfg.len()).rev() {
+                let mut out: BTreeSet<u16> = BTreeSet::new();
+                for succ in cfg.succs[b].iter() {
+                    out.extend(live_in[*succ].iter().cloned());
+                }
+                let mut inn: BTreeSet<u16> = out.difference(&kill[b]).cloned().collect();
+                inn.extend(gen[b].iter().cloned());
+
+                if inn != live_in[b] || out != live_out[b] {
+                    live_in[b] = inn;
+                    live_out[b] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        Liveness {
+            live_in: live_in,
+            live_out: live_out,
+        }
+    }
+
+    /// Slots live immediately after the expression at loc.
+    pub fn live_after(&self, ir: &IRPrototype, loc: Loc) -> BTreeSet<u16> {
+        let (b, i) = loc;
+        let mut live = self.live_out[b].clone();
+        for exp in ir.proto_ir[b].block_ir[i + 1..].iter().rev() {
+            for slot in Slots::defs(exp) { live.remove(&slot); }
+            live.extend(Slots::uses(exp));
+        }
+        live
+    }
+
+    pub fn is_live_after(&self, ir: &IRPrototype, loc: Loc, slot: u16) -> bool {
+        self.live_after(ir, loc).contains(&slot)
+    }
+}
+
+pub struct ReachingDefs {
+    pub reach_in: Vec<BTreeSet<Def>>,
+    pub reach_out: Vec<BTreeSet<Def>>,
+}
+
+impl ReachingDefs {
+    pub fn new(ir: &IRPrototype, cfg: &Cfg) -> ReachingDefs {
+        let mut reach_in: Vec<BTreeSet<Def>> = vec![BTreeSet::new(); cfg.len()];
+        let mut reach_out: Vec<BTreeSet<Def>> = vec![BTreeSet::new(); cfg.len()];
+        let order = cfg.reverse_post_order();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in order.iter().cloned() {
+                let mut inn: BTreeSet<Def> = BTreeSet::new();
+                for pred in cfg.preds[b].iter() {
+                    inn.extend(reach_out[*pred].iter().cloned());
+                }
+                let out = ReachingDefs::transfer(ir, b, inn.clone(), None);
+
+                if inn != reach_in[b] || out != reach_out[b] {
+                    reach_in[b] = inn;
+                    reach_out[b] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        ReachingDefs {
+            reach_in: reach_in,
+            reach_out: reach_out,
+        }
+    }
+
+    /// Applies the expressions of block b to the reaching set, stopping before index `until` if given.
+    fn transfer(ir: &IRPrototype, b: usize, mut reaching: BTreeSet<Def>, until: Option<usize>) -> BTreeSet<Def> {
+        let block = &ir.proto_ir[b];
+        let end = until.unwrap_or(block.block_ir.len());
+        for (i, exp) in block.block_ir[..end].iter().enumerate() {
+            for slot in Slots::defs(exp) {
+                reaching.retain(|d| d.slot != slot);
+                reaching.insert(Def { loc: (b, i), slot: slot });
+            }
+        }
+        reaching
+    }
+
+    /// Definitions that reach the expression at loc (before it executes).
+    pub fn reaching(&self, ir: &IRPrototype, loc: Loc) -> BTreeSet<Def> {
+        ReachingDefs::transfer(ir, loc.0, self.reach_in[loc.0].clone(), Some(loc.1))
+    }
+}
+
+pub struct DefUse {
+    pub uses: BTreeMap<Def, Vec<Loc>>,      //definition -> expressions reading it.
+    pub defs: BTreeMap<(Loc, u16), Vec<Def>>, //(use, slot) -> definitions that may supply it.
+}
+
+impl DefUse {
+    pub fn new(ir: &IRPrototype, reaching: &ReachingDefs) -> DefUse {
+        let mut uses: BTreeMap<Def, Vec<Loc>> = BTreeMap::new();
+        let mut defs: BTreeMap<(Loc, u16), Vec<Def>> = BTreeMap::new();
+
+        for block in ir.iter() {
+            let mut current = reaching.reach_in[block.id].clone();
+            for (i, exp) in block.iter().enumerate() {
+                let loc = (block.id, i);
+                for slot in Slots::uses(exp) {
+                    let supplying: Vec<Def> = current.iter().filter(|d| d.slot == slot).cloned().collect();
+                    for def in supplying.iter() {
+                        uses.entry(*def).or_default().push(loc);
+                    }
+                    defs.insert((loc, slot), supplying);
+                }
+                for slot in Slots::defs(exp) {
+                    current.retain(|d| d.slot != slot);
+                    let def = Def { loc: loc, slot: slot };
+                    current.insert(def);
+                    uses.entry(def).or_default();
+                }
+            }
+        }
+
+        DefUse {
+            uses: uses,
+            defs: defs,
+        }
+    }
+
+    /// Expressions reading the value the expression at loc writes to slot.
+    pub fn uses_of(&self, loc: Loc, slot: u16) -> &[Loc] {
+        self.uses.get(&Def { loc: loc, slot: slot }).map(|u| u.as_slice()).unwrap_or(&[])
+    }
+
+    /// Definitions that may supply slot to the expression at loc.
+    pub fn defs_of(&self, loc: Loc, slot: u16) -> &[Def] {
+        self.defs.get(&(loc, slot)).map(|d| d.as_slice()).unwrap_or(&[])
+    }
+
+    /// A definition is dead when nothing ever reads it.
+    pub fn is_dead(&self, loc: Loc, slot: u16) -> bool {
+        self.uses_of(loc, slot).is_empty()
+    }
+}
+
+/// All data-flow facts of one prototype, computed together so passes can share them.
+pub struct DataFlow {
+    pub liveness: Liveness,
+    pub reaching: ReachingDefs,
+    pub chains: DefUse,
+}
+
+impl DataFlow {
+    pub fn new(ir: &IRPrototype, cfg: &Cfg) -> DataFlow {
+        let liveness = Liveness::new(ir, cfg);
+        let reaching = ReachingDefs::new(ir, cfg);
+        let chains = DefUse::new(ir, &reaching);
+        DataFlow {
+            liveness: liveness,
+            reaching: reaching,
+            chains: chains,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        translator::Translator,
+    };
+    use super::*;
+
+    fn setup() -> (IRPrototype, Cfg) {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        (Translator{}.translate_blocks(blocks), cfg)
+    }
+
+    #[test]
+    fn test_slots() {
+        let mv = Exp::Move(Box::new(Exp::Var(2)), Box::new(Exp::Add(Box::new(Exp::Var(0)), Box::new(Exp::Var(1)))));
+        assert!(Slots::defs(&mv) == vec![2]);
+        assert!(Slots::uses(&mv) == vec![0, 1]);
+
+        let store = Exp::Move(Box::new(Exp::Table(Box::new(Exp::Var(0)), Box::new(Exp::Str(0)))), Box::new(Exp::Var(1)));
+        assert!(Slots::defs(&store).is_empty());
+        assert!(Slots::uses(&store) == vec![0, 1]);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let (ir, cfg) = setup();
+        let liveness = Liveness::new(&ir, &cfg);
+        //x and y are compared in every if. Slots 0 and 1 are never live at the entry.
+        assert!(liveness.live_in[0].is_empty(), "found: {:?}", liveness.live_in[0]);
+        assert!(liveness.live_in[4].is_empty(), "found: {:?}", liveness.live_in[4]);
+    }
+
+    #[test]
+    fn test_def_use() {
+        let (ir, cfg) = setup();
+        let df = DataFlow::new(&ir, &cfg);
+        //The first comparison reads both constants loaded at the start of the function.
+        let comparison = (0, 2);
+        assert!(df.chains.defs_of(comparison, 0) == [Def { loc: (0, 0), slot: 0 }]);
+        assert!(df.chains.defs_of(comparison, 1) == [Def { loc: (0, 1), slot: 1 }]);
+        assert!(df.chains.uses_of((0, 0), 0).contains(&comparison));
+        assert!(!df.chains.is_dead((0, 1), 1));
+    }
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 
+use crate::dis::lua_table::LuaValue;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Exp { //Expression.
     Error(String),
     Empty,
@@ -7,9 +10,16 @@ pub enum Exp { //Expression.
 
     //Pain
     Goto(u32), //jmp target.
+    Break,     //jump to the exit of the innermost loop.
+    Label(String),     //::name:: placed where a goto jumps to.
+    GotoLabel(String), //goto name, once the jump has a label to go to.
 
     //Slots
     Var(u16),
+    SVar(u16, u32), //slot, version. Only present while the IR is in SSA form.
+    Named(u16, String), //slot with the name of its local from the debug info.
+    Phi(Vec<Exp>),  //one SVar per predecessor, in the order of the CFG's predecessors.
+    List(Vec<Exp>), //comma separated expressions such as multiple assignment targets.
 
     //Slot Range. Replace anything that uses a range of bcis to an exp
     // that represents a block?
@@ -17,15 +27,23 @@ pub enum Exp { //Expression.
 
     //Constants
     Num(u16),   //index into number constant table.
-    Lit(u16),   //literal number not index.
+    Lit(i32),   //literal number not index, already sign corrected for its opcode.
     Str(u16),   //slot into the Strings table
+    CData(u16), //index into the kgc table of an FFI cdata constant.
     Uv(u16),    //slot into the uv table.
     Pri(u16),   //primitive literal such as nil, false, true -> 0, 1, 2.
+    Literal(LuaValue), //constant resolved from the prototype's constant tables.
     //Knil(u16, u16) //sets A->D to nil.
 
     //Tables
     Global, //_G in Table(Exp::Global, target)
     Table(Box<Exp>, Box<Exp>), //name.target
+    Method(Box<Exp>, Box<Exp>), //object:name, as a called function or the target of a method definition.
+    Tab(u16), //index into the kgc table of a template table.
+    Constructor(Box<Exp>, Vec<Exp>), //template table (Empty, Tab or Literal), items in order: values and Entry(key, value).
+    Entry(Box<Exp>, Box<Exp>), //key = value inside a constructor.
+    AppendMulti(Box<Exp>, Box<Exp>, Box<Exp>), //table, first index, values: table[i], table[i+1], ... = MultRes.
+    MultRes(u16), //every result of the last multi-result call or vararg, from slot onwards.
 
     //Binary Ops
     Add(Box<Exp>, Box<Exp>),
@@ -38,6 +56,7 @@ pub enum Exp { //Expression.
 
     //Unary
     Move(Box<Exp>, Box<Exp>), //assignment. move Box<Exp> into slot u16
+    Local(Box<Exp>, Box<Exp>), //declaration. local Var/List = value
     Unm(Box<Exp>),
     Len(Box<Exp>),
 
@@ -50,6 +69,7 @@ pub enum Exp { //Expression.
     Lt,     // <
     Lte,    // <=
     Equals, // ==
+    NotEquals, // ~=
 
     Comparison(Box<Exp>, Box<Exp>, Box<Exp>), //exp op exp
     Not(Box<Exp>),
@@ -57,18 +77,19 @@ pub enum Exp { //Expression.
     Or(Box<Exp>, Box<Exp>),
     
     //Branching
+    Do(Vec<Exp>), //do ... end block limiting the scope of the locals declared inside.
     UClo(u16, Box<Exp>),
     Jump(u32), //conditional, 'restrained' jumps.
-    If(Box<Exp>, u16, u16), //comparison, start of scope, end of scope.
-    Else(Box<Exp>, Box<Exp>),
-    While(Box<Exp>, Box<Exp>),
-    Repeat(Box<Exp>, Box<Exp>),
+    If(Box<Exp>, Vec<Exp>, Vec<Exp>), //condition, then statements, else statements.
+    While(Box<Exp>, Vec<Exp>),  //condition, body.
+    Repeat(Vec<Exp>, Box<Exp>), //body, condition ending the loop.
 
-    For(Box<Exp>, Box<Exp>, Box<Exp>, Box<Exp>), //start, stop, step, Range(start->end of scope)
-    IterFor(Box<Exp>, Box<Exp>), //iter call, iter block range
+    For(Box<Exp>, Box<Exp>, Box<Exp>, Box<Exp>, Vec<Exp>), //loop variable, start, stop, step (Empty when 1), body.
+    IterFor(Box<Exp>, Box<Exp>, Vec<Exp>), //loop variables, explist, body.
 
     //Functions
-    Func(u16, Box<Exp>), //proto index, func info?
+    Func(u16, Box<Exp>), //proto index, the Function it creates once the child prototype is known.
+    Function(Box<Exp>, Vec<Exp>), //parameter list, body.
     VarArg(Box<Exp>), //var args Range(from, to)
     ParamCount(u16),
     ReturnCount(u16),
@@ -88,53 +109,342 @@ impl fmt::Display for Exp {
             Exp::Error(v)               => result.push_str(&format!("error({})", v)),
             Exp::Range(v1, v2)          => result.push_str(&format!("{}->{}", v1, v2)),
             Exp::Goto(v)                => result.push_str(&format!("goto({})", v)),
+            Exp::Break                  => result.push_str("break"),
+            Exp::Label(v)               => result.push_str(&format!("::{}::", v)),
+            Exp::GotoLabel(v)           => result.push_str(&format!("goto {}", v)),
             Exp::Var(v)                 => result.push_str(&format!("var({})", v)),
+            Exp::SVar(v1, v2)           => result.push_str(&format!("var({})_{}", v1, v2)),
+            Exp::Named(_, v)            => result.push_str(v),
+            Exp::Phi(v)                 => result.push_str(&format!("phi({})", Exp::join(v))),
+            Exp::List(v)                => result.push_str(&Exp::join(v)),
             Exp::Num(v)                 => result.push_str(&format!("num({})", v)),
             Exp::Lit(v)                 => result.push_str(&format!("lit({})", v)),
             Exp::Str(v)                 => result.push_str(&format!("str({})", v)),
             Exp::Uv(v)                  => result.push_str(&format!("uv({})", v)),
             Exp::Pri(v)                 => result.push_str(&format!("pri({})", v)),
+            Exp::CData(v)               => result.push_str(&format!("cdata({})", v)),
+            Exp::Literal(v)             => result.push_str(&Exp::literal(v)),
+            Exp::Method(v1, v2)         => match Exp::identifier(v2) {
+                Some(name) => result.push_str(&format!("{}:{}", v1, name)),
+                None       => result.push_str(&format!("{}:[{}]", v1, v2)),
+            },
+            Exp::Tab(v)                 => result.push_str(&format!("tab({})", v)),
+            Exp::Constructor(v1, v2)    => result.push_str(&Exp::constructor(v1, v2)),
+            Exp::Entry(v1, v2)          => match Exp::identifier(v1) {
+                Some(name) => result.push_str(&format!("{} = {}", name, v2)),
+                None       => result.push_str(&format!("[{}] = {}", v1, v2)),
+            },
+            Exp::AppendMulti(v1, v2, v3) => result.push_str(&format!("{}[{}...] := {}", v1, v2, v3)),
+            Exp::MultRes(v)             => result.push_str(&format!("multres({})", v)),
             Exp::Global                 => result.push_str("_G"),
-            Exp::Table(v1, v2)          => result.push_str(&format!("{}.{}", v1, v2)),
+            Exp::Table(v1, v2)          => match (v1.as_ref(), Exp::identifier(v2)) {
+                (Exp::Global, Some(name)) => result.push_str(name),
+                (_, Some(name))           => result.push_str(&format!("{}.{}", v1, name)),
+                (_, None)                 => result.push_str(&format!("{}[{}]", v1, v2)),
+            },
             Exp::Add(v1, v2)            => result.push_str(&format!("({} + {})", v1, v2)),
             Exp::Sub(v1, v2)            => result.push_str(&format!("({} - {})", v1, v2)),
             Exp::Mul(v1, v2)            => result.push_str(&format!("({} * {})", v1, v2)),
             Exp::Div(v1, v2)            => result.push_str(&format!("({} / {})", v1, v2)),
             Exp::Mod(v1, v2)            => result.push_str(&format!("({} % {})", v1, v2)),
             Exp::Pow(v1, v2)            => result.push_str(&format!("({}^{})", v1, v2)),
-            Exp::Cat(v1, v2)            => result.push_str(&format!("({} .. {})", v1, v2)),
+            Exp::Cat(_, _)              => result.push_str(&format!("({})", Exp::cat_operands(self).iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" .. "))),
             Exp::Unm(v)                 => result.push_str(&format!("-({})", v)),
+            Exp::Move(v1, v2) if Exp::is_field(v1) && Exp::function(v2).is_some() => result.push_str(&Exp::definition(v1, v2)),
             Exp::Move(v1, v2)           => result.push_str(&format!("{} := {}", v1, v2)),
+            Exp::Local(v1, v2) if Exp::function(v2).is_some() => result.push_str(&format!("local {}", Exp::definition(v1, v2))),
+            Exp::Local(v1, v2) if Exp::is_nil(v2) => result.push_str(&format!("local {}", v1)),
+            Exp::Local(v1, v2)          => result.push_str(&format!("local {} := {}", v1, v2)),
             Exp::Len(v)                 => result.push_str(&format!("len({})", v)),
             Exp::Gt                     => result.push_str(">"),
             Exp::Gte                    => result.push_str(">="),
             Exp::Lt                     => result.push_str("<"),
             Exp::Lte                    => result.push_str("<="),
             Exp::Equals                 => result.push_str("=="),
+            Exp::NotEquals              => result.push_str("~="),
             Exp::Comparison(v1, v2, v3) => result.push_str(&format!("({} {} {})", v1, v2, v3)),
-            Exp::Not(v)                 => result.push_str(&format!("not({})", v)),
+            Exp::Not(v)                 => result.push_str(&format!("not {}", v)),
             Exp::And(v1, v2)            => result.push_str(&format!("({} and {})", v1, v2)),
             Exp::Or(v1, v2)             => result.push_str(&format!("({} or {})", v1, v2)),
+            Exp::Do(v) if v.iter().any(|e| e.to_string().contains('\n')) => result.push_str(&format!("do\n{}end", Exp::body(v))),
+            Exp::Do(v)                  => result.push_str(&format!("do {} end", v.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("; "))),
             Exp::UClo(v1, v2)           => result.push_str(&format!("uclo({}, {})", v1, v2)),
             Exp::Jump(v1)               => result.push_str(&format!("jmp({})", v1)),
-            Exp::If(v1, v2, v3)         => result.push_str(&format!("if {} then {}:{}", v1, v2, v3)),
-            Exp::Else(v1, v2)           => result.push_str(&format!("else {} range({})", v1, v2)),
-            Exp::While(v1, v2)          => result.push_str(&format!("while {} range({})", v1, v2)),
-            Exp::For(v1, v2, v3, v4)    => result.push_str(&format!("for start({}), stop({}), step({}), scope({})", v1, v2, v3, v4)),
-            Exp::Repeat(v1, v2)         => result.push_str(&format!("repeat {} range({})", v1, v2)),
+            Exp::If(v1, v2, v3)         => result.push_str(&Exp::if_statement(v1, v2, v3)),
+            Exp::While(v1, v2)          => result.push_str(&format!("while {} do\n{}end", v1, Exp::body(v2))),
+            Exp::For(v1, v2, v3, v4, v5) => match v4.as_ref() {
+                Exp::Empty => result.push_str(&format!("for {} = {}, {} do\n{}end", v1, v2, v3, Exp::body(v5))),
+                _          => result.push_str(&format!("for {} = {}, {}, {} do\n{}end", v1, v2, v3, v4, Exp::body(v5))),
+            },
+            Exp::Repeat(v1, v2)         => result.push_str(&format!("repeat\n{}until {}", Exp::body(v1), v2)),
+            Exp::Func(_, v) if matches!(v.as_ref(), Exp::Function(_, _)) => result.push_str(&v.to_string()),
             Exp::Func(v1, v2)           => result.push_str(&format!("func(proto:{}, info:{})", v1, v2)),
+            Exp::Function(v1, v2)       => result.push_str(&format!("function({})\n{}end", v1, Exp::body(v2))),
+            Exp::VarArg(v) if matches!(v.as_ref(), Exp::Empty) => result.push_str("..."),
             Exp::VarArg(v)              => result.push_str(&format!("varg({})", v)), 
             Exp::ParamCount(v)          => result.push_str(&format!("params({})", v)),
             Exp::ReturnCount(v)         => result.push_str(&format!("returns({})", v)),
             Exp::Call(v1, v2, v3)       => result.push_str(&format!("call({}, params({}), returns({}))", v1, v2, v3)),
-            Exp::Return(v)              => result.push_str(&format!("return({})", v)),
+            Exp::Return(v) if matches!(v.as_ref(), Exp::Empty) => result.push_str("return"),
+            Exp::Return(v)              => result.push_str(&format!("return {}", v)),
             Exp::IsT(v1, v2)            => result.push_str(&format!("IsT({}, {})", v2, v1)),
-            Exp::IterFor(v1, v2)        => result.push_str(&format!("Iter({}, {})", v1, v2)),
+            Exp::IterFor(v1, v2, v3)    => result.push_str(&format!("for {} in {} do\n{}end", v1, v2, Exp::body(v3))),
         }
         
         write!(f, "{}", result)
     }
 }
 
+impl Exp {
+    /// Lua source form of a constant.
+    fn literal(value: &LuaValue) -> String {
+        match value {
+            LuaValue::Str(s) => Exp::quote(s),
+            LuaValue::Double(d) if d.is_infinite() => (if *d > 0.0 { "math.huge" } else { "-math.huge" }).to_string(),
+            LuaValue::Double(d) if d.is_nan() => "(0/0)".to_string(),
+            v => v.to_string(),
+        }
+    }
+
+    /// Operands of a right nested chain of concatenations, left to right.
+    fn cat_operands(exp: &Exp) -> Vec<&Exp> {
+        let mut operands: Vec<&Exp> = vec![];
+        let mut rest = exp;
+        while let Exp::Cat(lhs, rhs) = rest {
+            operands.push(lhs);
+            rest = rhs;
+        }
+        operands.push(rest);
+        operands
+    }
+
+    pub fn is_nil(exp: &Exp) -> bool {
+        matches!(exp, Exp::Pri(0) | Exp::Literal(LuaValue::Nil))
+    }
+
+    /// Lua source form of a table constructor. Template entries come first, in array then hash order.
+    fn constructor(template: &Exp, items: &[Exp]) -> String {
+        let mut entries: Vec<String> = vec![];
+        if let Exp::Literal(LuaValue::Table(t)) = template {
+            for (i, v) in t.array_part.values.iter().enumerate() {
+                match (i, v) {
+                    (0, LuaValue::Nil) => (), //t[0] is stored in the array part but rarely set.
+                    (0, v)             => entries.push(format!("[0] = {}", Exp::literal(v))),
+                    (_, v)             => entries.push(Exp::literal(v)),
+                }
+            }
+            for (k, v) in t.hash_part.keys.iter().zip(t.hash_part.values.iter()) {
+                let entry = Exp::Entry(Box::new(Exp::Literal(k.clone())), Box::new(Exp::Literal(v.clone())));
+                entries.push(entry.to_string());
+            }
+        } else if !matches!(template, Exp::Empty) {
+            entries.push(format!("{}...", template));
+        }
+        entries.extend(items.iter().map(|e| e.to_string()));
+        format!("{{{}}}", entries.join(", "))
+    }
+
+    /// The function a closure creates, once its prototype has been inlined.
+    fn function(exp: &Exp) -> Option<(&Exp, &[Exp])> {
+        match exp {
+            Exp::Func(_, v) => match v.as_ref() {
+                Exp::Function(params, body) => Some((params.as_ref(), body.as_slice())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// True for the names function definitions accept besides locals: a global, or fields and methods of a
+    /// variable or global.
+    fn is_field(exp: &Exp) -> bool {
+        match exp {
+            Exp::Table(obj, name) | Exp::Method(obj, name) => Exp::identifier(name).is_some() && match obj.as_ref() {
+                Exp::Global | Exp::Var(_) | Exp::Named(_, _) => true,
+                obj => Exp::is_field(obj) && !matches!(obj, Exp::Method(_, _)),
+            },
+            _ => false,
+        }
+    }
+
+    /// function name(params) body end. A method leaves its self parameter implicit.
+    fn definition(name: &Exp, func: &Exp) -> String {
+        let (params, body) = Exp::function(func).unwrap();
+        let params = match (name, params) {
+            (Exp::Method(_, _), Exp::List(params)) if !params.is_empty() => Exp::join(&params[1..]),
+            (_, params) => params.to_string(),
+        };
+        format!("function {}({})\n{}end", name, params, Exp::body(body))
+    }
+
+    /// Lua source form of an if statement. An else part holding only another if becomes an elseif.
+    fn if_statement(cond: &Exp, then: &[Exp], otherwise: &[Exp]) -> String {
+        let mut s = format!("if {} then\n{}", cond, Exp::body(then));
+        match otherwise {
+            []                 => s.push_str("end"),
+            [Exp::If(c, t, o)] => s.push_str(&format!("else{}", Exp::if_statement(c, t, o))),
+            _                  => s.push_str(&format!("else\n{}end", Exp::body(otherwise))),
+        }
+        s
+    }
+
+    /// Statements of a nested block, one line each and indented by a tab.
+    fn body(stmts: &[Exp]) -> String {
+        stmts.iter()
+            .map(|e| e.to_string())
+            .flat_map(|s| s.lines().map(|l| format!("\t{}\n", l)).collect::<Vec<String>>())
+            .collect()
+    }
+
+    /// Logical negation of a condition. Negations are pushed through and/or and cancel out, which only
+    /// holds where the result is used for its truthiness. Equality flips, but an ordered comparison stays
+    /// wrapped since not (a < b) differs from a >= b when either side is NaN.
+    pub fn negate(self) -> Exp {
+        match self {
+            Exp::Not(v)      => *v,
+            Exp::Comparison(v1, op, v2) if matches!(*op, Exp::Equals | Exp::NotEquals) => {
+                let op = if *op == Exp::Equals { Exp::NotEquals } else { Exp::Equals };
+                Exp::Comparison(v1, Box::new(op), v2)
+            },
+            Exp::And(v1, v2) => Exp::Or(Box::new(v1.negate()), Box::new(v2.negate())),
+            Exp::Or(v1, v2)  => Exp::And(Box::new(v1.negate()), Box::new(v2.negate())),
+            v                => Exp::Not(Box::new(v)),
+        }
+    }
+
+    /// Quotes a string with Lua escape sequences.
+    fn quote(s: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in s.chars() {
+            match c {
+                '"'  => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if (c as u32) < 32 || c as u32 == 127 => quoted.push_str(&format!("\\{:03}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// The key of a table access when it can be written as .name.
+    pub fn identifier(key: &Exp) -> Option<&str> {
+        const KEYWORDS: [&str; 22] = [
+            "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+            "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+        ];
+        match key {
+            Exp::Literal(LuaValue::Str(s)) => {
+                let mut chars = s.chars();
+                let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !KEYWORDS.contains(&s.as_str());
+                if valid { Some(s) } else { None }
+            },
+            _ => None,
+        }
+    }
+
+    /// Calls f on this expression, then on every sub-expression, depth first. Returns true if any call did.
+    pub fn rewrite(&mut self, f: &mut dyn FnMut(&mut Exp) -> bool) -> bool {
+        let mut changed = f(self);
+        for child in self.children_mut() {
+            changed |= child.rewrite(f);
+        }
+        changed
+    }
+
+    fn join(exps: &[Exp]) -> String {
+        exps.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(", ")
+    }
+
+    /// Returns the sub-expressions directly contained by this expression.
+    pub fn children(&self) -> Vec<&Exp> {
+        match self {
+            Exp::Table(v1, v2)          |
+            Exp::Entry(v1, v2)          |
+            Exp::Method(v1, v2)         |
+            Exp::Add(v1, v2)            |
+            Exp::Sub(v1, v2)            |
+            Exp::Mul(v1, v2)            |
+            Exp::Div(v1, v2)            |
+            Exp::Mod(v1, v2)            |
+            Exp::Pow(v1, v2)            |
+            Exp::Cat(v1, v2)            |
+            Exp::Move(v1, v2)           |
+            Exp::Local(v1, v2)          |
+            Exp::IsT(v1, v2)            |
+            Exp::And(v1, v2)            |
+            Exp::Or(v1, v2)             => vec![v1.as_ref(), v2.as_ref()],
+            Exp::Comparison(v1, v2, v3) |
+            Exp::AppendMulti(v1, v2, v3) |
+            Exp::Call(v1, v2, v3)       => vec![v1.as_ref(), v2.as_ref(), v3.as_ref()],
+            Exp::For(v1, v2, v3, v4, v5) => vec![v1.as_ref(), v2.as_ref(), v3.as_ref(), v4.as_ref()].into_iter().chain(v5.iter()).collect(),
+            Exp::Unm(v)                 |
+            Exp::Len(v)                 |
+            Exp::Not(v)                 |
+            Exp::UClo(_, v)             |
+            Exp::Func(_, v)             |
+            Exp::VarArg(v)              |
+            Exp::Return(v)              => vec![v.as_ref()],
+            Exp::Phi(v)                 |
+            Exp::List(v)                |
+            Exp::Do(v)                  => v.iter().collect(),
+            Exp::Function(v1, v2)       => std::iter::once(v1.as_ref()).chain(v2.iter()).collect(),
+            Exp::Constructor(v1, v2)    => std::iter::once(v1.as_ref()).chain(v2.iter()).collect(),
+            Exp::If(v1, v2, v3)         => std::iter::once(v1.as_ref()).chain(v2.iter()).chain(v3.iter()).collect(),
+            Exp::IterFor(v1, v2, v3)    => vec![v1.as_ref(), v2.as_ref()].into_iter().chain(v3.iter()).collect(),
+            Exp::While(v1, v2)          => std::iter::once(v1.as_ref()).chain(v2.iter()).collect(),
+            Exp::Repeat(v1, v2)         => v1.iter().chain(std::iter::once(v2.as_ref())).collect(),
+            _                           => vec![],
+        }
+    }
+
+    /// Returns the sub-expressions directly contained by this expression as mutable references.
+    pub fn children_mut(&mut self) -> Vec<&mut Exp> {
+        match self {
+            Exp::Table(v1, v2)          |
+            Exp::Entry(v1, v2)          |
+            Exp::Method(v1, v2)         |
+            Exp::Add(v1, v2)            |
+            Exp::Sub(v1, v2)            |
+            Exp::Mul(v1, v2)            |
+            Exp::Div(v1, v2)            |
+            Exp::Mod(v1, v2)            |
+            Exp::Pow(v1, v2)            |
+            Exp::Cat(v1, v2)            |
+            Exp::Move(v1, v2)           |
+            Exp::Local(v1, v2)          |
+            Exp::IsT(v1, v2)            |
+            Exp::And(v1, v2)            |
+            Exp::Or(v1, v2)             => vec![v1.as_mut(), v2.as_mut()],
+            Exp::Comparison(v1, v2, v3) |
+            Exp::AppendMulti(v1, v2, v3) |
+            Exp::Call(v1, v2, v3)       => vec![v1.as_mut(), v2.as_mut(), v3.as_mut()],
+            Exp::For(v1, v2, v3, v4, v5) => vec![v1.as_mut(), v2.as_mut(), v3.as_mut(), v4.as_mut()].into_iter().chain(v5.iter_mut()).collect(),
+            Exp::Unm(v)                 |
+            Exp::Len(v)                 |
+            Exp::Not(v)                 |
+            Exp::UClo(_, v)             |
+            Exp::Func(_, v)             |
+            Exp::VarArg(v)              |
+            Exp::Return(v)              => vec![v.as_mut()],
+            Exp::Phi(v)                 |
+            Exp::List(v)                |
+            Exp::Do(v)                  => v.iter_mut().collect(),
+            Exp::Function(v1, v2)       => std::iter::once(v1.as_mut()).chain(v2.iter_mut()).collect(),
+            Exp::Constructor(v1, v2)    => std::iter::once(v1.as_mut()).chain(v2.iter_mut()).collect(),
+            Exp::If(v1, v2, v3)         => std::iter::once(v1.as_mut()).chain(v2.iter_mut()).chain(v3.iter_mut()).collect(),
+            Exp::IterFor(v1, v2, v3)    => vec![v1.as_mut(), v2.as_mut()].into_iter().chain(v3.iter_mut()).collect(),
+            Exp::While(v1, v2)          => std::iter::once(v1.as_mut()).chain(v2.iter_mut()).collect(),
+            Exp::Repeat(v1, v2)         => v1.iter_mut().chain(std::iter::once(v2.as_mut())).collect(),
+            _                           => vec![],
+        }
+    }
+}
+
 pub struct IrGen{}
 impl IrGen{}
\ No newline at end of file
@@ -0,0 +1,421 @@
+// Recovers where local variables are declared and how long they stay in scope.
+//
+// With debug info, the variable ranges of the prototype give the exact declaration points and scopes.
+// Stripped prototypes fall back to data-flow heuristics: a slot becomes a local where it is first written
+// with a value that is not a temporary consumed by the next few instructions.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    dis::prototyper::{Prototype, VarInfo},
+    ir::{
+        cfg::Cfg,
+        dataflow::{DataFlow, Def, Loc, Slots},
+        ir_gen::Exp,
+        ssa::Ssa,
+        translator::IRPrototype,
+    },
+};
+
+pub struct Locals{}
+impl Locals {
+    /// Rewrites declaring expressions into Exp::Local. The IR must still hold one expression per bytecode
+    /// instruction so that expressions can be matched against the debug info's pcs.
+    pub fn declare(ir: &mut IRPrototype, cfg: &Cfg, pt: &Prototype) {
+        if pt.var_info.is_empty() {
+            Locals::declare_from_heuristics(ir, cfg, pt);
+        } else {
+            Locals::declare_from_debug_info(ir, cfg, pt);
+        }
+    }
+
+    fn declare_from_debug_info(ir: &mut IRPrototype, cfg: &Cfg, pt: &Prototype) {
+        let (declared, before_branches) = Locals::declare_branch_values(ir, cfg, pt);
+        Locals::name_uses(ir, pt);
+        for block in ir.iter_mut() {
+            let mut groups: Vec<Vec<usize>> = vec![]; //indices of the expressions declaring one statement.
+            let mut names: Vec<Vec<(u16, String)>> = vec![]; //slots each group declares, with their names.
+            let mut scopes: Vec<(usize, usize)> = vec![]; //(first index, end index) of locals ending mid-block.
+            let last = block.start_index + block.block_ir.len() - 1;
+
+            for i in 0..block.block_ir.len() {
+                let index = block.start_index + i;
+                let vars: Vec<(u16, &VarInfo)> = pt.vars_starting_at(index + 1).into_iter()
+                    .filter(|(_, v)| !v.is_internal())
+                    .collect();
+                if vars.is_empty() || declared.contains(&(block.id, i)) { continue; }
+
+                let slots: BTreeSet<u16> = vars.iter().map(|(slot, _)| *slot).collect();
+                if let Some(group) = Locals::find_declaring(&block.block_ir, i, &slots) {
+                    for (_, v) in vars.iter() {
+                        let end = (v.end_pc as usize).saturating_sub(1); //first instruction out of scope.
+                        if end > index && end < last {
+                            scopes.push((group[0], end - block.start_index));
+                        }
+                    }
+                    names.push(vars.iter().map(|(slot, v)| (*slot, v.name.clone())).collect());
+                    groups.push(group);
+                }
+            }
+
+            let firsts: Vec<usize> = groups.iter().map(|g| g[0]).collect();
+            let removed = Locals::apply_groups(&mut block.block_ir, groups);
+            let remap = |i: usize| i - removed.iter().filter(|r| **r < i).count();
+            for (first, names) in firsts.into_iter().zip(names.iter()) {
+                if let Exp::Local(dst, _) = &mut block.block_ir[remap(first)] {
+                    Locals::name(dst, names);
+                }
+            }
+            let scopes: Vec<(usize, usize)> = scopes.into_iter().map(|(s, e)| (remap(s), remap(e))).collect();
+            Locals::apply_scopes(&mut block.block_ir, scopes);
+        }
+
+        //Before the first write in the block, or else before the branch ending it.
+        for (b, named) in before_branches {
+            let exps = &mut ir.proto_ir[b].block_ir;
+            let at = exps.iter().position(|e| Locals::assigns(e, &named)).unwrap_or_else(|| Ssa::terminator_index(exps));
+            exps.insert(at, Exp::Local(Box::new(named), Box::new(Exp::Pri(0))));
+        }
+    }
+
+    /// The debug info starts a local whose value is computed by branches, such as local x = a and b, after the
+    /// write of one arm only. Every write reaching its uses from before it starts assigns the local: the one
+    /// dominating the others declares it, without one a local statement is put in the block dominating them all.
+    /// Returns the declaring writes of the debug info that were handled here, and where the local statements go.
+    fn declare_branch_values(ir: &mut IRPrototype, cfg: &Cfg, pt: &Prototype) -> (BTreeSet<Loc>, Vec<(usize, Exp)>) {
+        let df = DataFlow::new(ir, cfg);
+        let idom = cfg.dominators();
+        let index_of = |loc: Loc| ir.proto_ir[loc.0].start_index + loc.1;
+        let mut declared: BTreeSet<Loc> = BTreeSet::new();
+        let mut writes: Vec<(u16, String, Vec<Loc>, Vec<Loc>)> = vec![];
+        for block in ir.iter() {
+            for (i, exp) in block.iter().enumerate() {
+                let index = block.start_index + i;
+                let (slot, name, end) = match pt.vars_starting_at(index + 1).as_slice() {
+                    [(slot, v)] if !v.is_internal() && Locals::declarable_defs(exp) == [*slot] => (*slot, v.name.clone(), v.end_pc as usize),
+                    _ => continue,
+                };
+                //The writes reaching the reads in scope from before the local starts. The declaring one may be
+                //unreachable, after a goto.
+                let mut defs: BTreeSet<Loc> = BTreeSet::from([(block.id, i)]);
+                for (b, reader) in ir.iter().enumerate() {
+                    for (j, e) in reader.iter().enumerate() {
+                        if index_of((b, j)) <= index || index_of((b, j)) + 1 >= end || !Slots::uses(e).contains(&slot) { continue; }
+                        defs.extend(df.chains.defs_of((b, j), slot).iter().map(|d| d.loc).filter(|d| index_of(*d) <= index));
+                    }
+                }
+                if defs.len() > 1 {
+                    //Reads before the local starts, such as the test of a copied value, only see these writes too.
+                    let reads: Vec<Loc> = defs.iter()
+                        .flat_map(|d| df.chains.uses_of(*d, slot).iter().cloned())
+                        .filter(|u| index_of(*u) <= index && df.chains.defs_of(*u, slot).iter().all(|d| defs.contains(&d.loc)))
+                        .collect();
+                    declared.insert((block.id, i));
+                    writes.push((slot, name, defs.into_iter().collect(), reads));
+                }
+            }
+        }
+
+        let mut before_branches: Vec<(usize, Exp)> = vec![];
+        for (slot, name, defs, reads) in writes {
+            for r in reads.iter() {
+                let exp = &mut ir.proto_ir[r.0].block_ir[r.1];
+                if defs.contains(r) || !Slots::defs(exp).contains(&slot) {
+                    Locals::name(exp, &[(slot, name.clone())]);
+                }
+            }
+            let named = Exp::Named(slot, name);
+            let dominating = defs.iter().find(|d| defs.iter().all(|o| {
+                if d.0 == o.0 { d.1 <= o.1 } else { Cfg::dominates(&idom, d.0, o.0) }
+            })).filter(|d| Locals::declarable_defs(&ir.proto_ir[d.0].block_ir[d.1]) == [slot]);
+            for d in defs.iter() {
+                let exp = &mut ir.proto_ir[d.0].block_ir[d.1];
+                if Some(d) == dominating {
+                    let value = match std::mem::replace(exp, Exp::Empty) {
+                        Exp::Move(_, v) => *v,
+                        call => call,
+                    };
+                    *exp = Exp::Local(Box::new(named.clone()), Box::new(value));
+                    continue;
+                }
+                let dst = match exp {
+                    Exp::Move(dst, _) | Exp::Call(_, _, dst) => dst,
+                    Exp::IsT(mv, _) => match mv.as_mut() {
+                        Exp::Move(dst, _) => dst,
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                if Slots::range(dst) == [slot] { **dst = named.clone(); }
+            }
+            if dominating.is_none() {
+                //Unreachable writes do not decide where the local goes.
+                let mut blocks = defs.iter().map(|d| d.0).filter(|b| *b == 0 || idom[*b].is_some());
+                if let Some(first) = blocks.next() {
+                    let at = blocks.fold(first, |a, b| Locals::common_dominator(&idom, a, b));
+                    before_branches.push((at, named));
+                }
+            }
+        }
+        (declared, before_branches)
+    }
+
+    /// Closest block dominating both a and b.
+    fn common_dominator(idom: &[Option<usize>], a: usize, b: usize) -> usize {
+        let mut a = a;
+        while !Cfg::dominates(idom, a, b) {
+            a = match idom[a] {
+                Some(d) if d != a => d,
+                _ => return 0,
+            };
+        }
+        a
+    }
+
+    /// True for a write of named, as a plain move or as the copy of a test.
+    fn assigns(exp: &Exp, named: &Exp) -> bool {
+        match exp {
+            Exp::Move(dst, _) => dst.as_ref() == named,
+            Exp::IsT(mv, _) => Locals::assigns(mv, named),
+            _ => false,
+        }
+    }
+
+    /// Renames the slots read or written while a variable of the debug info is in scope to the variable.
+    fn name_uses(ir: &mut IRPrototype, pt: &Prototype) {
+        for block in ir.iter_mut() {
+            let start = block.start_index;
+            for (i, exp) in block.block_ir.iter_mut().enumerate() {
+                //Active variables occupy the slots from 0 on, in the order they were declared.
+                let names: Vec<(u16, String)> = pt.var_info.iter()
+                    .filter(|v| v.is_active(start + i))
+                    .enumerate()
+                    .filter(|(_, v)| !v.is_internal())
+                    .map(|(slot, v)| (slot as u16, v.name.clone()))
+                    .collect();
+                Locals::name(exp, &names);
+            }
+        }
+    }
+
+    /// Replaces Var(slot) by Named(slot, name) for each of the names.
+    fn name(exp: &mut Exp, names: &[(u16, String)]) {
+        if names.is_empty() { return; }
+        exp.rewrite(&mut |e| match e {
+            Exp::Var(slot) => match names.iter().find(|(s, _)| s == slot) {
+                Some((slot, name)) => {
+                    *e = Exp::Named(*slot, name.clone());
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        });
+    }
+
+    /// Walks backwards from index i collecting the expressions that write every slot in slots.
+    fn find_declaring(exps: &[Exp], i: usize, slots: &BTreeSet<u16>) -> Option<Vec<usize>> {
+        let mut remaining = slots.clone();
+        let mut group: Vec<usize> = vec![];
+        let mut j = i + 1;
+        while j > 0 && !remaining.is_empty() {
+            j -= 1;
+            let defs = Locals::declarable_defs(&exps[j]);
+            if defs.is_empty() || !defs.iter().all(|d| remaining.contains(d)) { break; }
+            for d in defs.iter() { remaining.remove(d); }
+            group.insert(0, j);
+        }
+        if remaining.is_empty() { Some(group) } else { None }
+    }
+
+    /// Slots written by an expression that can become a local statement.
+    fn declarable_defs(exp: &Exp) -> Vec<u16> {
+        match exp {
+            Exp::Move(dst, _) if Slots::is_slot_target(dst) => Slots::defs(exp),
+            Exp::Call(_, _, _) | Exp::VarArg(_) => Slots::defs(exp),
+            _ => vec![],
+        }
+    }
+
+    /// Replaces each group of declaring expressions by one Exp::Local. Returns the indices that were removed.
+    fn apply_groups(exps: &mut Vec<Exp>, groups: Vec<Vec<usize>>) -> Vec<usize> {
+        let mut removed: Vec<usize> = vec![];
+        for group in groups.into_iter().rev() {
+            let mut dsts: Vec<Exp> = vec![];
+            let mut values: Vec<Exp> = vec![];
+            for j in group.iter().rev() {
+                let exp = if *j == group[0] {
+                    std::mem::replace(&mut exps[*j], Exp::Empty)
+                } else {
+                    removed.push(*j);
+                    exps.remove(*j)
+                };
+                dsts.splice(0..0, Slots::defs(&exp).into_iter().map(Exp::Var));
+                values.insert(0, match exp {
+                    Exp::Move(_, src) => *src,
+                    other => other,
+                });
+            }
+            exps[group[0]] = Locals::local(dsts, values);
+        }
+        removed
+    }
+
+    fn local(mut dsts: Vec<Exp>, mut values: Vec<Exp>) -> Exp {
+        let dst = if dsts.len() == 1 { dsts.remove(0) } else { Exp::List(dsts) };
+        let value = if values.len() == 1 { values.remove(0) } else { Exp::List(values) };
+        Exp::Local(Box::new(dst), Box::new(value))
+    }
+
+    /// Wraps [start, end) ranges of expressions in do ... end blocks, innermost first.
+    fn apply_scopes(exps: &mut Vec<Exp>, mut scopes: Vec<(usize, usize)>) {
+        //Locals ending at the same place share one block.
+        scopes.sort_by_key(|(start, end)| (*end, *start));
+        scopes.dedup_by_key(|(_, end)| *end);
+        scopes.sort_by_key(|(start, end)| end - start);
+
+        for k in 0..scopes.len() {
+            let (start, end) = scopes[k];
+            let body: Vec<Exp> = exps.drain(start..end).collect();
+            exps.insert(start, Exp::Do(body));
+            let shrink = end - start - 1;
+            for (s, e) in scopes.iter_mut().skip(k + 1) {
+                if *s > start { *s -= shrink; }
+                if *e >= end { *e -= shrink; }
+            }
+        }
+    }
+
+    fn declare_from_heuristics(ir: &mut IRPrototype, cfg: &Cfg, pt: &Prototype) {
+        let df = DataFlow::new(ir, cfg);
+        let idom = cfg.dominators();
+
+        for b in 0..ir.proto_ir.len() {
+            let mut groups: Vec<Vec<usize>> = vec![];
+            for (i, exp) in ir.proto_ir[b].iter().enumerate() {
+                let slots = Locals::declarable_defs(exp);
+                if slots.is_empty() { continue; }
+                let is_local = slots.iter().all(|slot| {
+                    *slot >= pt.header.num_params as u16 && Locals::starts_local(ir, &df, &idom, (b, i), *slot)
+                });
+                if is_local {
+                    groups.push(vec![i]);
+                }
+            }
+            Locals::apply_groups(&mut ir.proto_ir[b].block_ir, groups);
+        }
+    }
+
+    /// A write starts a new local when the previous value of the slot is dead, the value is not a temporary
+    /// and it alone supplies every read of it.
+    fn starts_local(ir: &IRPrototype, df: &DataFlow, idom: &[Option<usize>], loc: (usize, usize), slot: u16) -> bool {
+        let before_is_live = if loc.1 == 0 {
+            df.liveness.live_in[loc.0].contains(&slot)
+        } else {
+            df.liveness.is_live_after(ir, (loc.0, loc.1 - 1), slot)
+        };
+        if before_is_live { return false; }
+
+        let def = Def { loc: loc, slot: slot };
+        let uses = df.chains.uses_of(loc, slot);
+        let is_temporary = uses.len() == 1
+            && uses[0].0 == loc.0
+            && uses[0].1 > loc.1
+            && df.chains.defs_of(uses[0], slot) == [def];
+        if is_temporary { return false; }
+
+        uses.iter().all(|u| {
+            df.chains.defs_of(*u, slot) == [def] && Cfg::dominates(idom, loc.0, u.0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        translator::Translator,
+    };
+    use super::*;
+
+    fn setup(file: &str, skip: usize) -> (IRPrototype, Cfg, Prototype) {
+        let mut ptr = Prototyper::new(file);
+        for _ in 0..skip { ptr.next().unwrap(); }
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        (Translator{}.translate_blocks(blocks), cfg, pt)
+    }
+
+    fn locals(ir: &IRPrototype) -> Vec<String> {
+        ir.iter().flat_map(|b| b.iter())
+            .filter(|e| matches!(e, Exp::Local(_, _)))
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_declare_from_debug_info() {
+        let (mut ir, cfg, pt) = setup("dec.lua", 1); //dec.loops
+        Locals::declare(&mut ir, &cfg, &pt);
+        let declared = locals(&ir);
+        //x, y and z are declared where they are first set, the variables of the for loops are declared by their loops.
+        assert!(declared == ["local x := lit(0)", "local y := lit(0)", "local z := {}"], "found: {:?}", declared);
+    }
+
+    #[test]
+    fn test_declare_branch_values() {
+        //local is_child_reflected = child ~= nil and child.reflector_source ~= nil: the debug info starts it after
+        //the true arm, the false arm writes it as well.
+        let (mut ir, cfg, pt) = setup("beam_system_client.lua", 16);
+        Locals::declare(&mut ir, &cfg, &pt);
+        let declared = locals(&ir);
+        assert!(declared.iter().any(|l| l == "local is_child_reflected"), "found: {:?}", declared);
+        assert!(!declared.iter().any(|l| l.starts_with("local is_child_reflected :=")), "found: {:?}", declared);
+        //It is declared before the branch, which assigns it in both arms.
+        let assigned = |block: usize, value: &str| ir.proto_ir[block].iter().any(|e| e.to_string() == format!("is_child_reflected := {}", value));
+        assert!(ir.proto_ir[13].iter().any(|e| e.to_string() == "local is_child_reflected"));
+        assert!(assigned(15, "pri(1)") && assigned(16, "pri(2)"));
+        //The write after a goto declaring can_process_child is unreachable, the branch before it declares it.
+        assert!(ir.proto_ir[17].iter().any(|e| e.to_string() == "local can_process_child"));
+        assert!(!declared.iter().any(|l| l.starts_with("local can_process_child :=")), "found: {:?}", declared);
+    }
+
+    #[test]
+    fn test_declare_from_heuristics() {
+        let (mut ir, cfg, pt) = setup("singleif.ljc", 0);
+        Locals::declare(&mut ir, &cfg, &pt);
+        //Every constant is compared once right after it is loaded, so nothing is a local.
+        assert!(locals(&ir).is_empty(), "found: {:?}", locals(&ir));
+    }
+
+    #[test]
+    fn test_apply_groups() {
+        let mut exps = vec![
+            Exp::Move(Box::new(Exp::Var(0)), Box::new(Exp::Lit(1))),
+            Exp::Move(Box::new(Exp::Var(1)), Box::new(Exp::Lit(2))),
+            Exp::Jump(4),
+        ];
+        let removed = Locals::apply_groups(&mut exps, vec![vec![0, 1]]);
+        assert!(removed == vec![1]);
+        assert!(exps.len() == 2);
+        assert!(exps[0].to_string() == "local var(0), var(1) := lit(1), lit(2)", "found: {}", exps[0]);
+    }
+
+    #[test]
+    fn test_apply_groups_knil() {
+        //KNIL declares several uninitialised locals at once.
+        let mut exps = vec![Exp::Move(Box::new(Exp::Range(0, 2)), Box::new(Exp::Pri(0)))];
+        Locals::apply_groups(&mut exps, vec![vec![0]]);
+        assert!(exps[0].to_string() == "local var(0), var(1), var(2)", "found: {}", exps[0]);
+    }
+
+    #[test]
+    fn test_apply_scopes() {
+        let mut exps: Vec<Exp> = (0..5).map(Exp::Var).collect();
+        Locals::apply_scopes(&mut exps, vec![(1, 4), (2, 3)]);
+        assert!(exps.len() == 3);
+        assert!(exps[1].to_string() == "do var(1); do var(2) end; var(3) end", "found: {}", exps[1]);
+    }
+}
@@ -1,4 +1,9 @@
-mod blocker;
-mod ir_gen;
-mod translator;
-mod rules;
+pub mod blocker;
+pub mod cfg;
+pub mod dataflow;
+pub mod ir_gen;
+pub mod locals;
+pub mod translator;
+pub mod rules;
+pub mod ssa;
+pub mod structurer;
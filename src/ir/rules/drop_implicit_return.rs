@@ -0,0 +1,47 @@
+use super::{Rule, RuleContext};
+use crate::ir::{
+    ir_gen::Exp,
+    translator::IRPrototype,
+};
+
+/// Removes the RET0 LuaJIT appends to a function body that does not end with a return.
+pub struct DropImplicitReturn {}
+impl Rule for DropImplicitReturn {
+    fn name(&self) -> &'static str { "drop_implicit_return" }
+
+    fn apply(&self, ir: &mut IRPrototype, _ctx: &RuleContext) -> bool {
+        //A trailing return without values is redundant even when it was written out.
+        match ir.proto_ir.last_mut() {
+            Some(block) if block.block_ir.last() == Some(&Exp::Return(Box::new(Exp::Empty))) => {
+                block.block_ir.pop();
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        cfg::Cfg,
+        translator::Translator,
+    };
+    use super::*;
+
+    #[test]
+    fn test_drop_implicit_return() {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        let mut ir = Translator{}.translate_blocks(blocks);
+        let ctx = RuleContext::new(&pt, &cfg);
+        assert!(DropImplicitReturn{}.apply(&mut ir, &ctx));
+        //The final block only held the RET0 every if jumps to when its condition fails.
+        assert!(ir.proto_ir.last().unwrap().block_ir.is_empty());
+        assert!(!DropImplicitReturn{}.apply(&mut ir, &ctx));
+    }
+}
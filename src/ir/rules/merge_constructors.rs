@@ -0,0 +1,440 @@
+use super::{Rule, RuleContext};
+use crate::{
+    dis::lua_table::LuaValue,
+    ir::{
+        dataflow::{Liveness, Loc, Slots},
+        ir_gen::Exp,
+        translator::IRPrototype,
+    },
+};
+
+/// Folds the stores into a fresh table (TNEW or TDUP) that follow it, before the table escapes, into its constructor.
+pub struct MergeConstructors {}
+impl Rule for MergeConstructors {
+    fn name(&self) -> &'static str { "merge_constructors" }
+
+    fn apply(&self, ir: &mut IRPrototype, ctx: &RuleContext) -> bool {
+        let mut changed = false;
+        for b in 0..ir.proto_ir.len() {
+            //Innermost constructors come last, so they are folded before the constructors they are stored into.
+            for i in (0..ir.proto_ir[b].block_ir.len()).rev() {
+                changed |= MergeConstructors::fold(ir, ctx, b, i);
+            }
+        }
+        changed
+    }
+}
+
+impl MergeConstructors {
+    /// Folds the stores following the constructor at exps[i] into it. Values computed into temporaries just
+    /// before a store are inlined into the constructor when nothing else reads them. A TSETM appending the
+    /// results of a call or vararg becomes the last item.
+    fn fold(ir: &mut IRPrototype, ctx: &RuleContext, b: usize, i: usize) -> bool {
+        let exps = &ir.proto_ir[b].block_ir;
+        let table = match MergeConstructors::constructor_slot(&exps[i]) {
+            Some(table) => table,
+            None => return false,
+        };
+        let liveness = Liveness::new(ir, ctx.cfg);
+        let mut items = match MergeConstructors::constructor_items(&exps[i]) {
+            Some(items) => items,
+            None => return false,
+        };
+
+        let mut pending: Vec<(u16, Exp)> = vec![]; //temporaries and MULTRES written since the last folded store.
+        let mut folded = 0; //number of expressions after i that are folded.
+        for (j, exp) in exps.iter().enumerate().skip(i + 1) {
+            if Slots::uses(exp).contains(&table) && !MergeConstructors::stores_into(exp, table) { break; }
+            match exp {
+                Exp::Move(dst, value) if Slots::is_slot_target(dst) => match dst.as_ref() {
+                    Exp::Var(s) if *s > table => {
+                        //A value computed from pending temporaries, such as a field of a fetched upvalue, takes them in.
+                        let reads = Slots::uses(value);
+                        let value = match pending.iter().any(|(p, _)| reads.contains(p)) {
+                            true => match MergeConstructors::consume(ir, &liveness, (b, j), &mut pending, &[value]) {
+                                Some(mut value) => value.remove(0),
+                                None => break,
+                            },
+                            false => value.as_ref().clone(),
+                        };
+                        pending.push((*s, value));
+                    },
+                    _ => break,
+                },
+                //A call returning a single value, or its results left in MULTRES for a TSETM to append.
+                Exp::Call(f, params, returns) => {
+                    let s = match Slots::range(returns).as_slice() {
+                        [s] if *s > table => *s,
+                        _ => break,
+                    };
+                    let operands = match MergeConstructors::consume(ir, &liveness, (b, j), &mut pending, &[f, params]) {
+                        Some(operands) => operands,
+                        None => break,
+                    };
+                    let mut operands = operands.into_iter();
+                    let (f, params) = (operands.next().unwrap(), operands.next().unwrap());
+                    let returns = match returns.as_ref() {
+                        Exp::MultRes(_) => returns.clone(),
+                        _ => Box::new(Exp::Empty), //the single result is used as an expression.
+                    };
+                    pending.push((s, Exp::Call(Box::new(f), Box::new(params), returns)));
+                },
+                Exp::VarArg(results) => match results.as_ref() {
+                    Exp::MultRes(s) if *s > table && pending.is_empty() => pending.push((*s, Exp::VarArg(Box::new(Exp::Empty)))),
+                    _ => break,
+                },
+                Exp::AppendMulti(t, base, values) if Slots::slot(t) == Some(table) => {
+                    let positional = items.iter().filter(|e| !matches!(e, Exp::Entry(_, _))).count();
+                    let first = match base.as_ref() {
+                        Exp::Lit(k) => *k,
+                        Exp::Literal(LuaValue::SInt(k)) => *k,
+                        _ => break,
+                    };
+                    if first as usize != positional + 1 || !matches!(values.as_ref(), Exp::MultRes(_)) { break; }
+                    match MergeConstructors::consume(ir, &liveness, (b, j), &mut pending, &[values]) {
+                        Some(mut values) => items.push(values.remove(0)),
+                        None => break,
+                    }
+                    //Nothing can follow the open end of the constructor.
+                    folded = j - i;
+                    break;
+                },
+                Exp::Move(dst, value) => {
+                    let key = match dst.as_ref() {
+                        Exp::Table(t, key) if Slots::slot(t) == Some(table) => key,
+                        _ => break,
+                    };
+                    let mut operands = match MergeConstructors::consume(ir, &liveness, (b, j), &mut pending, &[key, value]) {
+                        Some(operands) => operands.into_iter(),
+                        None => break,
+                    };
+                    let (key, value) = (operands.next().unwrap(), operands.next().unwrap());
+                    if !MergeConstructors::add_item(&mut items, key, value) { break; }
+                    folded = j - i;
+                },
+                _ => break,
+            }
+        }
+        if folded == 0 { return false; }
+
+        let exps = &mut ir.proto_ir[b].block_ir;
+        exps.drain((i + 1)..=(i + folded));
+        if let Some(constructor) = MergeConstructors::constructor_mut(&mut exps[i]) {
+            *constructor = Exp::Constructor(Box::new(Exp::Empty), items);
+        }
+        true
+    }
+
+    /// True for a store into table that does not read the table otherwise, the only use that can be folded.
+    fn stores_into(exp: &Exp, table: u16) -> bool {
+        let t = Some(table);
+        match exp {
+            Exp::Move(dst, value) => match dst.as_ref() {
+                Exp::Table(tbl, key) => Slots::slot(tbl) == t && !Slots::uses(key).contains(&table) && !Slots::uses(value).contains(&table),
+                _ => false,
+            },
+            Exp::AppendMulti(tbl, _, values) => Slots::slot(tbl) == t && !Slots::uses(values).contains(&table),
+            _ => false,
+        }
+    }
+
+    /// Inlines the pending temporaries into the operands of the expression at loc, wherever they are read in them.
+    /// Every pending temporary must be read once by the operands, in order, and be dead afterwards. None when they
+    /// are not, pending is then unchanged.
+    fn consume(ir: &IRPrototype, liveness: &Liveness, loc: Loc, pending: &mut Vec<(u16, Exp)>, operands: &[&Exp]) -> Option<Vec<Exp>> {
+        let slots: Vec<u16> = pending.iter().map(|(p, _)| *p).collect();
+        let mut consumed: Vec<u16> = vec![];
+        for operand in operands.iter() {
+            MergeConstructors::reads(operand, &slots, &mut consumed);
+        }
+        let in_order = consumed == slots;
+        //A temporary the expression writes again, such as the function slot a call returns into, is dead after it.
+        let defs = Slots::defs(&ir.proto_ir[loc.0].block_ir[loc.1]);
+        if !in_order || consumed.iter().any(|s| !defs.contains(s) && liveness.is_live_after(ir, loc, *s)) { return None; }
+
+        let mut values = pending.drain(..).map(|(_, v)| v);
+        let inlined = operands.iter().map(|e| {
+            let mut e = (*e).clone();
+            MergeConstructors::inline(&mut e, &slots, &mut values);
+            e
+        });
+        Some(inlined.collect())
+    }
+
+    /// Appends the slots of exp that are in slots, in the order they are read. A range reads all its slots.
+    fn reads(exp: &Exp, slots: &[u16], read: &mut Vec<u16>) {
+        match exp {
+            Exp::Var(s) | Exp::MultRes(s) if slots.contains(s) => read.push(*s),
+            Exp::Range(_, _) => read.extend(Slots::range(exp).into_iter().filter(|s| slots.contains(s))),
+            _ => {
+                for child in exp.children() {
+                    MergeConstructors::reads(child, slots, read);
+                }
+            },
+        }
+    }
+
+    /// Replaces the reads of slots in exp by the next values, in the order reads finds them. The values put in are
+    /// not searched again.
+    fn inline(exp: &mut Exp, slots: &[u16], values: &mut dyn Iterator<Item = Exp>) {
+        match exp {
+            Exp::Var(s) | Exp::MultRes(s) if slots.contains(s) => *exp = values.next().unwrap(),
+            Exp::Range(_, _) => {
+                let range = Slots::range(exp);
+                if range.iter().any(|s| slots.contains(s)) {
+                    *exp = Exp::List(range.into_iter().map(|s| match slots.contains(&s) {
+                        true => values.next().unwrap(),
+                        false => Exp::Var(s),
+                    }).collect());
+                }
+            },
+            _ => {
+                for child in exp.children_mut() {
+                    MergeConstructors::inline(child, slots, values);
+                }
+            },
+        }
+    }
+
+    /// Slot a constructor is assigned to, either by a plain move or by a local declaration.
+    fn constructor_slot(exp: &Exp) -> Option<u16> {
+        match exp {
+            Exp::Move(dst, src) | Exp::Local(dst, src) => match src.as_ref() {
+                Exp::Constructor(_, _) => Slots::slot(dst),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn constructor_mut(exp: &mut Exp) -> Option<&mut Exp> {
+        match exp {
+            Exp::Move(_, src) | Exp::Local(_, src) => Some(src.as_mut()),
+            _ => None,
+        }
+    }
+
+    /// Items of a constructor with its template table expanded. Nil values of the template are placeholders of
+    /// entries that are stored later. None while the template has not been resolved yet.
+    fn constructor_items(exp: &Exp) -> Option<Vec<Exp>> {
+        let (template, items) = match exp {
+            Exp::Move(_, src) | Exp::Local(_, src) => match src.as_ref() {
+                Exp::Constructor(template, items) => (template, items),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let mut expanded: Vec<Exp> = vec![];
+        match template.as_ref() {
+            Exp::Empty => (),
+            Exp::Literal(LuaValue::Table(t)) => {
+                for (i, v) in t.array_part.values.iter().enumerate() {
+                    match (i, v) {
+                        (0, LuaValue::Nil) => (),
+                        (0, v) => expanded.push(MergeConstructors::entry(Exp::Literal(LuaValue::SInt(0)), Exp::Literal(v.clone()))),
+                        (_, v) => expanded.push(Exp::Literal(v.clone())),
+                    }
+                }
+                for (k, v) in t.hash_part.keys.iter().zip(t.hash_part.values.iter()) {
+                    expanded.push(MergeConstructors::entry(Exp::Literal(k.clone()), Exp::Literal(v.clone())));
+                }
+            },
+            _ => return None,
+        }
+        expanded.extend(items.iter().cloned());
+        Some(expanded)
+    }
+
+    fn entry(key: Exp, value: Exp) -> Exp {
+        Exp::Entry(Box::new(key), Box::new(value))
+    }
+
+    /// Adds key = value to items: as the next positional item, into a nil placeholder or as a keyed entry.
+    /// Returns false if the key is already set, since the constructor could not express the overwrite.
+    fn add_item(items: &mut Vec<Exp>, key: Exp, value: Exp) -> bool {
+        let positional = items.iter().filter(|e| !matches!(e, Exp::Entry(_, _))).count();
+        let index = match &key {
+            Exp::Lit(k) if *k > 0 => Some(*k as usize),
+            Exp::Literal(LuaValue::SInt(k)) if *k > 0 => Some(*k as usize),
+            _ => None,
+        };
+        let nil = Exp::Literal(LuaValue::Nil);
+
+        if let Some(index) = index {
+            if index == positional + 1 {
+                items.push(value);
+                return true;
+            }
+            if index <= positional {
+                let slot = items.iter_mut().filter(|e| !matches!(e, Exp::Entry(_, _))).nth(index - 1).unwrap();
+                if *slot != nil { return false; }
+                *slot = value;
+                return true;
+            }
+        }
+
+        let existing = items.iter_mut().find(|e| matches!(e, Exp::Entry(k, _) if **k == key));
+        match existing {
+            Some(Exp::Entry(_, v)) if **v == nil => **v = value,
+            Some(_) => return false,
+            None => items.push(MergeConstructors::entry(key, value)),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        cfg::Cfg,
+        rules::{MergeLiterals, PassManager},
+        translator::{IRBlock, Translator},
+    };
+    use super::*;
+
+    fn var(slot: u16) -> Box<Exp> {
+        Box::new(Exp::Var(slot))
+    }
+
+    fn store(table: u16, key: Exp, value: u16) -> Exp {
+        Exp::Move(Box::new(Exp::Table(var(table), Box::new(key))), var(value))
+    }
+
+    fn block(exps: Vec<Exp>) -> IRPrototype {
+        IRPrototype { proto_ir: vec![IRBlock { id: 0, start_index: 0, block_ir: exps }] }
+    }
+
+    /// The edges of a single block, which has none.
+    fn single() -> Cfg {
+        Cfg { succs: vec![vec![]], preds: vec![vec![]] }
+    }
+
+    fn string(s: &str) -> Exp {
+        Exp::Literal(LuaValue::Str(s.to_string()))
+    }
+
+    #[test]
+    fn test_fold_stores() {
+        //local t = {a = 1, 2, [x] = t2}
+        let mut ir = block(vec![
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            Exp::Move(var(2), Box::new(Exp::Literal(LuaValue::SInt(1)))),
+            store(1, string("a"), 2),
+            Exp::Move(var(2), Box::new(Exp::Literal(LuaValue::SInt(2)))),
+            store(1, Exp::Lit(1), 2),
+            store(1, Exp::Var(0), 3),
+            Exp::Return(var(1)),
+        ]);
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        assert!(MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &single())));
+        let exps = &ir.proto_ir[0].block_ir;
+        assert!(exps.len() == 2, "found: {:?}", exps);
+        assert!(exps[0].to_string() == "var(1) := {a = 1, 2, [var(0)] = var(3)}", "found: {}", exps[0]);
+    }
+
+    #[test]
+    fn test_fold_multres() {
+        //local t = {x, f()}: the results of the call are appended by TSETM from index 2 on.
+        let f = Exp::Table(Box::new(Exp::Global), Box::new(string("f")));
+        let mut ir = block(vec![
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            Exp::Move(var(2), var(0)),
+            store(1, Exp::Lit(1), 2),
+            Exp::Move(var(2), Box::new(f)),
+            Exp::Call(var(2), Box::new(Exp::Empty), Box::new(Exp::MultRes(2))),
+            Exp::AppendMulti(var(1), Box::new(Exp::Literal(LuaValue::SInt(2))), Box::new(Exp::MultRes(2))),
+            Exp::Return(var(1)),
+        ]);
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        assert!(MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &single())));
+        let exps = &ir.proto_ir[0].block_ir;
+        assert!(exps.len() == 2, "found: {:?}", exps);
+        assert!(exps[0].to_string() == "var(1) := {var(0), call(f, params((empty)), returns(multres(2)))}", "found: {}", exps[0]);
+
+        //local t = {...}
+        let mut ir = block(vec![
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            Exp::VarArg(Box::new(Exp::MultRes(2))),
+            Exp::AppendMulti(var(1), Box::new(Exp::Lit(1)), Box::new(Exp::MultRes(2))),
+        ]);
+        assert!(MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &single())));
+        let exps = &ir.proto_ir[0].block_ir;
+        assert!(exps.len() == 1 && exps[0].to_string() == "var(1) := {...}", "found: {:?}", exps);
+    }
+
+    #[test]
+    fn test_fold_computed_items() {
+        //local t = {M.new(), #x}: the items take more than one expression to compute.
+        let mut ir = block(vec![
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            Exp::Move(var(2), Box::new(Exp::Uv(0))),
+            Exp::Move(var(2), Box::new(Exp::Table(var(2), Box::new(string("new"))))),
+            Exp::Call(var(2), Box::new(Exp::Empty), Box::new(Exp::Range(2, 2))),
+            store(1, Exp::Lit(1), 2),
+            Exp::Move(var(3), var(0)),
+            Exp::Move(var(2), Box::new(Exp::Len(var(3)))),
+            store(1, Exp::Lit(2), 2),
+            Exp::Return(var(1)),
+        ]);
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        assert!(MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &single())));
+        let exps = &ir.proto_ir[0].block_ir;
+        assert!(exps.len() == 2, "found: {:?}", exps);
+        assert!(exps[0].to_string() == "var(1) := {call(uv(0).new, params((empty)), returns((empty))), len(var(0))}", "found: {}", exps[0]);
+    }
+
+    #[test]
+    fn test_keep_temporaries_read_by_loop() {
+        //A block looping to itself reads var(2) at the top of the body, the value stored is still needed there.
+        let mut ir = block(vec![
+            Exp::Move(var(3), var(2)),
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            Exp::Move(var(2), Box::new(Exp::Literal(LuaValue::SInt(1)))),
+            store(1, string("a"), 2),
+        ]);
+        let cfg = Cfg { succs: vec![vec![0]], preds: vec![vec![0]] };
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        assert!(!MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &cfg)));
+        assert!(ir.proto_ir[0].block_ir.len() == 4);
+    }
+
+    #[test]
+    fn test_stop_at_escape() {
+        //The table is read before the second store, so only the first store is folded.
+        let mut ir = block(vec![
+            Exp::Move(var(1), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![]))),
+            store(1, string("a"), 0),
+            Exp::Move(var(2), var(1)),
+            store(1, string("b"), 0),
+        ]);
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        assert!(MergeConstructors{}.apply(&mut ir, &RuleContext::new(&pt, &single())));
+        let exps = &ir.proto_ir[0].block_ir;
+        assert!(exps.len() == 3);
+        assert!(exps[0].to_string() == "var(1) := {a = var(0)}", "found: {}", exps[0]);
+    }
+
+    #[test]
+    fn test_fold_prototype() {
+        let mut ptr = Prototyper::new("beam_system_client.lua");
+        let pt = (0..20).map(|_| ptr.next().unwrap()).last().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        let mut ir = Translator{}.translate_blocks(blocks);
+        let before = ir.iter().map(|b| b.block_ir.len()).sum::<usize>();
+        let pm = PassManager::new(vec![Box::new(MergeLiterals{}), Box::new(MergeConstructors{})]);
+        pm.run(&mut ir, &RuleContext::new(&pt, &cfg));
+        let after = ir.iter().map(|b| b.block_ir.len()).sum::<usize>();
+        assert!(after < before);
+        //The template's nil placeholders are filled in by the stores that follow the TDUP.
+        let exps: Vec<String> = ir.iter().flat_map(|b| b.iter()).map(|e| e.to_string()).collect();
+        assert!(exps.iter().any(|e| e.starts_with("var(23) := {length = 0, left = ")), "found: {:?}", exps);
+    }
+}
@@ -1,9 +1,142 @@
-use super::Rule;
-use crate::ir::rules::{IRBlock};
+use super::{Rule, RuleContext};
+use crate::{
+    dis::lua_table::LuaValue,
+    ir::{
+        ir_gen::Exp,
+        translator::IRPrototype,
+    },
+};
 
+/// Replaces constant table indices (Num, Str, Pri, Lit, CData, Tab) with the literal values they refer to.
 pub struct MergeLiterals {}
 impl Rule for MergeLiterals {
-    fn apply(block: &mut IRBlock) {
-        
+    fn name(&self) -> &'static str { "merge_literals" }
+
+    fn apply(&self, ir: &mut IRPrototype, ctx: &RuleContext) -> bool {
+        let mut changed = false;
+        for exp in ir.iter_mut().flat_map(|b| b.iter_mut()) {
+            changed |= exp.rewrite(&mut |e| {
+                if let Exp::AppendMulti(_, base, _) = e {
+                    if let Exp::Num(d) = base.as_ref() {
+                        //The first index of TSETM is stored in the low word of the number's bits.
+                        let index = match ctx.pt.constants.kn(*d) {
+                            Some(LuaValue::Double(n)) => n.to_bits() as u32 as i32,
+                            Some(LuaValue::SInt(n)) => *n,
+                            _ => return false,
+                        };
+                        **base = Exp::Literal(LuaValue::SInt(index));
+                        return true;
+                    }
+                }
+                match MergeLiterals::resolve(e, ctx) {
+                    Some(value) => {
+                        *e = Exp::Literal(value);
+                        true
+                    },
+                    None => false,
+                }
+            });
+        }
+        changed
+    }
+}
+
+impl MergeLiterals {
+    fn resolve(exp: &Exp, ctx: &RuleContext) -> Option<LuaValue> {
+        let constants = &ctx.pt.constants;
+        match exp {
+            Exp::Num(d) => constants.kn(*d).cloned(),
+            Exp::Str(d) => constants.string(*d).map(|s| LuaValue::Str(s.to_string())),
+            Exp::Pri(0) => Some(LuaValue::Nil),
+            Exp::Pri(1) => Some(LuaValue::False),
+            Exp::Pri(2) => Some(LuaValue::True),
+            Exp::Lit(d) => Some(LuaValue::SInt(*d)),
+            Exp::CData(d) => match constants.kgc(*d) {
+                Some(v @ (LuaValue::I64(_) | LuaValue::U64(_) | LuaValue::ComplexNum(_))) => Some(v.clone()),
+                _ => None,
+            },
+            Exp::Tab(d) => match constants.kgc(*d) {
+                Some(t @ LuaValue::Table(_)) => Some(t.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::{Prototype, Prototyper};
+    use crate::ir::{
+        blocker::Blocker,
+        cfg::Cfg,
+        translator::Translator,
+    };
+    use super::*;
+
+    fn setup() -> (IRPrototype, Cfg, Prototype) {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        (Translator{}.translate_blocks(blocks), cfg, pt)
+    }
+
+    #[test]
+    fn test_merge_literals() {
+        let (mut ir, cfg, pt) = setup();
+        let ctx = RuleContext::new(&pt, &cfg);
+        assert!(MergeLiterals{}.apply(&mut ir, &ctx));
+        assert!(!MergeLiterals{}.apply(&mut ir, &ctx), "a second application has nothing left to resolve");
+
+        let block = &ir.proto_ir[1];
+        assert!(block.block_ir[0].to_string() == "var(0) := print", "found: {}", block.block_ir[0]);
+        assert!(block.block_ir[1].to_string() == "var(1) := 1", "found: {}", block.block_ir[1]);
+    }
+
+    #[test]
+    fn test_merge_table_literals() {
+        let mut ptr = Prototyper::new("beam_system_client.lua");
+        let pt = (0..20).map(|_| ptr.next().unwrap()).last().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        let mut ir = Translator{}.translate_blocks(blocks);
+        MergeLiterals{}.apply(&mut ir, &RuleContext::new(&pt, &cfg));
+        let exps: Vec<String> = ir.iter().flat_map(|b| b.iter()).map(|e| e.to_string()).collect();
+        //TDUP copies a template table with one hash entry.
+        assert!(exps.iter().any(|e| e.ends_with(":= {length = 0}")), "found: {:?}", exps);
+
+    }
+
+    #[test]
+    fn test_merge_knum() {
+        //KNUM loads the number constant its D operand indexes, not a slot.
+        let mut ptr = Prototyper::new("beam_system_client.lua");
+        let mut found = false;
+        while let Some(pt) = ptr.next() {
+            let blocks = Blocker{}.make_blocks(&pt);
+            let cfg = Cfg::new(&blocks);
+            let mut ir = Translator{}.translate_blocks(blocks);
+            MergeLiterals{}.apply(&mut ir, &RuleContext::new(&pt, &cfg));
+            for bci in pt.instructions.iter().filter(|b| b.op == 40) {
+                let value = pt.constants.kn(bci.d()).unwrap();
+                assert!(matches!(value, LuaValue::Double(_) | LuaValue::SInt(_)));
+                let expected = format!("var({}) := {}", bci.a(), Exp::Literal(value.clone()));
+                assert!(ir.iter().flat_map(|b| b.iter()).any(|e| e.to_string() == expected), "missing: {}", expected);
+                found = true;
+            }
+        }
+        assert!(found);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (_, cfg, pt) = setup();
+        let ctx = RuleContext::new(&pt, &cfg);
+        assert!(MergeLiterals::resolve(&Exp::Lit(-1), &ctx) == Some(LuaValue::SInt(-1)));
+        assert!(MergeLiterals::resolve(&Exp::Pri(2), &ctx) == Some(LuaValue::True));
+        assert!(MergeLiterals::resolve(&Exp::Str(0), &ctx) == Some(LuaValue::Str("print".to_string())));
+        assert!(MergeLiterals::resolve(&Exp::Num(0), &ctx).is_none());
+        assert!(Exp::Literal(LuaValue::Str("a\"b\n".to_string())).to_string() == "\"a\\\"b\\n\"");
     }
-}
\ No newline at end of file
+}
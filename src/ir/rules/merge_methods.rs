@@ -0,0 +1,204 @@
+use super::{Rule, RuleContext};
+use crate::ir::{
+    dataflow::{Liveness, Slots},
+    ir_gen::Exp,
+    translator::IRPrototype,
+};
+
+/// Recovers obj:name(...) calls from the self-passing MOV and TGETS that precede a call, and
+/// function obj:name() definitions from functions whose first parameter is self.
+pub struct MergeMethods {}
+impl Rule for MergeMethods {
+    fn name(&self) -> &'static str { "merge_methods" }
+
+    fn apply(&self, ir: &mut IRPrototype, ctx: &RuleContext) -> bool {
+        let mut changed = false;
+        for block in ir.iter_mut() {
+            let mut k = block.block_ir.len();
+            while k > 0 {
+                k -= 1;
+                if MergeMethods::merge_call(&mut block.block_ir, k) {
+                    changed = true;
+                    k -= 2; //the two removed expressions preceded the call.
+                }
+            }
+        }
+        for b in 0..ir.proto_ir.len() {
+            let mut i = ir.proto_ir[b].block_ir.len();
+            while i > 0 {
+                i -= 1;
+                let len = ir.proto_ir[b].block_ir.len();
+                if MergeMethods::merge_definition(ir, (b, i), ctx) {
+                    changed = true;
+                    i -= len - ir.proto_ir[b].block_ir.len(); //the closure's FNEW and the object's fetch were folded.
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl MergeMethods {
+    /// Folds MOV A+1, obj and TGETS A, obj, "name" into the call of A at exps[k].
+    fn merge_call(exps: &mut Vec<Exp>, k: usize) -> bool {
+        let (a, first_param) = match MergeMethods::call(&exps[k]) {
+            Some(Exp::Call(f, params, _)) => match (f.as_ref(), Slots::range(params).first()) {
+                (Exp::Var(a), Some(p)) if *p == a + 1 => (*a, *p),
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        //The function is fetched by the closest write of A before the call.
+        let j = match (0..k).rev().find(|j| Slots::defs(&exps[*j]).contains(&a)) {
+            Some(j) if j > 0 => j,
+            _ => return false,
+        };
+        let (obj, name) = match (&exps[j - 1], &exps[j]) {
+            (Exp::Move(self_dst, self_src), Exp::Move(f_dst, f_src)) => match (self_dst.as_ref(), self_src.as_ref(), f_dst.as_ref(), f_src.as_ref()) {
+                (Exp::Var(s), o, Exp::Var(_), Exp::Table(t, name))
+                    if *s == first_param && Slots::slot(o).is_some_and(|o| o != first_param) && Slots::slot(t) == Slots::slot(o)
+                        && Exp::identifier(name).is_some() => (o.clone(), name.as_ref().clone()),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        //Arguments must not overwrite the function, self or the object before the call.
+        let overwritten = exps[(j + 1)..k].iter().any(|e| {
+            Slots::defs(e).iter().any(|d| *d == a || *d == first_param || Some(*d) == Slots::slot(&obj))
+        });
+        if overwritten { return false; }
+
+        if let Some(Exp::Call(f, params, _)) = MergeMethods::call_mut(&mut exps[k]) {
+            **f = Exp::Method(Box::new(obj), Box::new(name));
+            MergeMethods::drop_first_param(params);
+        }
+        exps.drain((j - 1)..=j);
+        true
+    }
+
+    /// Turns t := function(self, ...); obj.name := t into the method definition obj:name := function(...).
+    fn merge_definition(ir: &mut IRPrototype, loc: (usize, usize), ctx: &RuleContext) -> bool {
+        let i = loc.1;
+        if i == 0 { return false; }
+        let exps = &ir.proto_ir[loc.0].block_ir;
+        let (t, func) = match &exps[i - 1] {
+            Exp::Move(t, func) => match (t.as_ref(), func.as_ref()) {
+                (Exp::Var(t), Exp::Func(index, _)) => (*t, (*index, func.as_ref().clone())),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        let (obj, name) = match &exps[i] {
+            Exp::Move(dst, src) if src.as_ref() == &Exp::Var(t) => match dst.as_ref() {
+                Exp::Table(obj, name) if !matches!(obj.as_ref(), Exp::Global) && Exp::identifier(name).is_some() => (obj.clone(), name.clone()),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        if Slots::uses(&Exp::Table(obj.clone(), name.clone())).contains(&t) { return false; }
+        let takes_self = ctx.child(func.0).is_some_and(|c| c.params.first().map(|p| p.as_str()) == Some("self"));
+        if !takes_self || Liveness::new(ir, ctx.cfg).is_live_after(ir, loc, t) { return false; }
+
+        let fetch = MergeMethods::object_fetch(ir, loc, &obj, t, ctx);
+        let exps = &mut ir.proto_ir[loc.0].block_ir;
+        let folded = fetch.is_some();
+        exps[i] = Exp::Move(Box::new(Exp::Method(fetch.map_or(obj, Box::new), name)), Box::new(func.1));
+        exps.remove(i - 1);
+        if folded { exps.remove(i - 2); }
+        true
+    }
+
+    /// The global, upvalue or field fetched into the object slot right before the closure, when the slot is
+    /// only read by the definition at loc.
+    fn object_fetch(ir: &IRPrototype, loc: (usize, usize), obj: &Exp, t: u16, ctx: &RuleContext) -> Option<Exp> {
+        let (b, i) = loc;
+        let o = match obj {
+            Exp::Var(o) if *o != t && i >= 2 => *o,
+            _ => return None,
+        };
+        match &ir.proto_ir[b].block_ir[i - 2] {
+            Exp::Move(dst, src) if dst.as_ref() == &Exp::Var(o) && matches!(src.as_ref(), Exp::Table(_, _) | Exp::Uv(_))
+                && !Slots::uses(src).contains(&t) && !Liveness::new(ir, ctx.cfg).is_live_after(ir, loc, o) => Some(src.as_ref().clone()),
+            _ => None,
+        }
+    }
+
+    /// Removes the self slot from the front of a call's params.
+    fn drop_first_param(params: &mut Exp) {
+        match params {
+            Exp::Range(first, last) if *first < *last => *first += 1,
+            Exp::Range(_, _) | Exp::Var(_) => *params = Exp::Empty,
+            Exp::List(items) if !items.is_empty() => {
+                MergeMethods::drop_first_param(&mut items[0]);
+                if items[0] == Exp::Empty { items.remove(0); }
+            },
+            _ => (),
+        }
+    }
+
+    /// The call made by a statement, a return or a local declaration.
+    fn call(exp: &Exp) -> Option<&Exp> {
+        match exp {
+            Exp::Call(_, _, _) => Some(exp),
+            Exp::Return(v) | Exp::Local(_, v) if matches!(v.as_ref(), Exp::Call(_, _, _)) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn call_mut(exp: &mut Exp) -> Option<&mut Exp> {
+        match exp {
+            Exp::Call(_, _, _) => Some(exp),
+            Exp::Return(v) | Exp::Local(_, v) if matches!(v.as_ref(), Exp::Call(_, _, _)) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        cfg::Cfg,
+        rules::{MergeLiterals, PassManager, ProtoSummary},
+        translator::Translator,
+    };
+    use super::*;
+
+    #[test]
+    fn test_merge_methods() {
+        let mut ptr = Prototyper::new("beam_system_client.lua");
+        let mut protos: BTreeMap<usize, ProtoSummary> = BTreeMap::new();
+        let pm = PassManager::new(vec![Box::new(MergeLiterals{}), Box::new(MergeMethods{})]);
+        let mut calls = 0;
+        let mut definitions = 0;
+        let mut names: Vec<String> = vec![];
+        while let Some(pt) = ptr.next() {
+            let blocks = Blocker{}.make_blocks(&pt);
+            let cfg = Cfg::new(&blocks);
+            let mut ir = Translator{}.translate_blocks(blocks);
+            pm.run(&mut ir, &RuleContext::with_protos(&pt, &cfg, &protos));
+            for exp in ir.iter().flat_map(|b| b.iter()) {
+                match exp {
+                    Exp::Call(f, _, _) if matches!(f.as_ref(), Exp::Method(_, _)) => calls += 1,
+                    Exp::Move(dst, src) if matches!(dst.as_ref(), Exp::Method(_, _)) => {
+                        assert!(matches!(src.as_ref(), Exp::Func(_, _)));
+                        definitions += 1;
+                        names.push(dst.to_string());
+                    },
+                    _ => (),
+                }
+            }
+            protos.insert(pt.header.id, ProtoSummary::new(&pt));
+        }
+        assert!(calls > 0);
+        //Every child prototype taking self is defined as a method of the module table.
+        assert!(definitions > 20, "found: {}", definitions);
+        //The module table fetched right before the closure is folded into the name.
+        assert!(names.iter().any(|n| n == "ClientBeamSystem:finalize_setup"), "found: {:?}", names);
+        assert!(!names.iter().any(|n| n.starts_with("var(")), "found: {:?}", names);
+    }
+}
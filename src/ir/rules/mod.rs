@@ -1,7 +1,243 @@
+mod drop_implicit_return;
+mod merge_constructors;
 mod merge_literals;
+mod merge_methods;
 
-use crate::ir::translator::{IRPrototype, IRBlock};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    dis::prototyper::Prototype,
+    ir::{
+        cfg::Cfg,
+        ir_gen::Exp,
+        translator::IRPrototype,
+    },
+};
+
+pub use drop_implicit_return::DropImplicitReturn;
+pub use merge_constructors::MergeConstructors;
+pub use merge_literals::MergeLiterals;
+pub use merge_methods::MergeMethods;
+
+/// What is known about a prototype once it has been decompiled, for the rules of its parent.
+pub struct ProtoSummary {
+    pub params: Vec<String>, //parameter names, empty without debug info.
+    pub num_params: u8,
+    pub is_vararg: bool,
+    pub body: Vec<Exp>, //structured statements, empty until the prototype has been structured.
+}
+
+impl ProtoSummary {
+    pub fn new(pt: &Prototype) -> ProtoSummary {
+        ProtoSummary {
+            params: pt.param_names().into_iter().map(|s| s.to_string()).collect(),
+            num_params: pt.header.num_params,
+            is_vararg: pt.header.flags.is_vararg,
+            body: vec![],
+        }
+    }
+}
+
+static NO_PROTOS: BTreeMap<usize, ProtoSummary> = BTreeMap::new();
+
+/// Prototype information available to rules while they rewrite its IR.
+pub struct RuleContext<'a> {
+    pub pt: &'a Prototype,
+    pub cfg: &'a Cfg, //edges between the blocks of the IR. Rules rewrite blocks but never their edges.
+    pub protos: &'a BTreeMap<usize, ProtoSummary>, //prototypes decompiled so far by id. Children precede their parents.
+}
+
+impl<'a> RuleContext<'a> {
+    pub fn new(pt: &'a Prototype, cfg: &'a Cfg) -> RuleContext<'a> {
+        RuleContext {
+            pt: pt,
+            cfg: cfg,
+            protos: &NO_PROTOS,
+        }
+    }
+
+    pub fn with_protos(pt: &'a Prototype, cfg: &'a Cfg, protos: &'a BTreeMap<usize, ProtoSummary>) -> RuleContext<'a> {
+        RuleContext {
+            pt: pt,
+            cfg: cfg,
+            protos: protos,
+        }
+    }
+
+    /// Summary of the child prototype created by FNEW with the given operand.
+    pub fn child(&self, index: u16) -> Option<&'a ProtoSummary> {
+        self.pt.child_id(index).and_then(|id| self.protos.get(&id))
+    }
+}
 
 pub trait Rule {
-    fn apply(block: &mut IRBlock);
-}
\ No newline at end of file
+    /// Name used to enable, disable and order the rule from the command line.
+    fn name(&self) -> &'static str;
+
+    /// Rewrites the IR in place. Returns true if anything changed.
+    fn apply(&self, ir: &mut IRPrototype, ctx: &RuleContext) -> bool;
+}
+
+/// Runs rules in order, repeatedly, until none of them changes the IR anymore.
+pub struct PassManager {
+    rules: Vec<Box<dyn Rule>>,
+    disabled: BTreeSet<String>,
+    max_iterations: usize,
+}
+
+impl Default for PassManager {
+    fn default() -> PassManager {
+        PassManager::new(vec![
+            Box::new(MergeLiterals{}),
+            Box::new(MergeConstructors{}),
+            Box::new(MergeMethods{}),
+            Box::new(DropImplicitReturn{}),
+        ])
+    }
+}
+
+impl PassManager {
+    const MAX_ITERATIONS: usize = 64;
+
+    pub fn new(rules: Vec<Box<dyn Rule>>) -> PassManager {
+        PassManager {
+            rules: rules,
+            disabled: BTreeSet::new(),
+            max_iterations: PassManager::MAX_ITERATIONS,
+        }
+    }
+
+    pub fn rule_names(&self) -> Vec<&'static str> {
+        self.rules.iter().map(|r| r.name()).collect()
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        self.check_name(name)?;
+        self.disabled.insert(name.to_string());
+        Ok(())
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<(), String> {
+        self.check_name(name)?;
+        self.disabled.remove(name);
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.contains(name)
+    }
+
+    /// Moves the named rules to the front in the given order. Unnamed rules keep their relative order after them.
+    pub fn reorder(&mut self, names: &[&str]) -> Result<(), String> {
+        for name in names.iter() {
+            self.check_name(name)?;
+        }
+        let mut ordered: Vec<Box<dyn Rule>> = vec![];
+        for name in names.iter() {
+            if let Some(i) = self.rules.iter().position(|r| r.name() == *name) {
+                ordered.push(self.rules.remove(i));
+            }
+        }
+        ordered.append(&mut self.rules);
+        self.rules = ordered;
+        Ok(())
+    }
+
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations;
+    }
+
+    /// Applies every enabled rule until a fixed point is reached. Returns the number of iterations that changed the IR.
+    pub fn run(&self, ir: &mut IRPrototype, ctx: &RuleContext) -> usize {
+        let mut iterations = 0;
+        while iterations < self.max_iterations {
+            let mut changed = false;
+            for rule in self.rules.iter().filter(|r| self.is_enabled(r.name())) {
+                changed |= rule.apply(ir, ctx);
+            }
+            if !changed { break; }
+            iterations += 1;
+        }
+        iterations
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), String> {
+        if self.rules.iter().any(|r| r.name() == name) {
+            Ok(())
+        } else {
+            Err(format!("Unknown rule: {}. Known rules: {}", name, self.rule_names().join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        ir_gen::Exp,
+        translator::Translator,
+    };
+    use super::*;
+
+    //Turns one Lit(n) with n > 0 into Lit(n - 1) per application.
+    struct CountDown{}
+    impl Rule for CountDown {
+        fn name(&self) -> &'static str { "count_down" }
+        fn apply(&self, ir: &mut IRPrototype, _ctx: &RuleContext) -> bool {
+            for exp in ir.iter_mut().flat_map(|b| b.iter_mut()) {
+                if let Exp::Move(_, src) = exp {
+                    if let Exp::Lit(n) = src.as_mut() {
+                        if *n > 0 {
+                            *n -= 1;
+                            return true;
+                        }
+                    }
+                }
+            }
+            false
+        }
+    }
+
+    struct Nothing{}
+    impl Rule for Nothing {
+        fn name(&self) -> &'static str { "nothing" }
+        fn apply(&self, _ir: &mut IRPrototype, _ctx: &RuleContext) -> bool { false }
+    }
+
+    fn setup() -> (IRPrototype, Cfg, Prototype) {
+        let mut ptr = Prototyper::new("singleif.ljc");
+        let pt = ptr.next().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        (Translator{}.translate_blocks(blocks), cfg, pt)
+    }
+
+    #[test]
+    fn test_run_to_fixed_point() {
+        let (mut ir, cfg, pt) = setup();
+        let pm = PassManager::new(vec![Box::new(CountDown{}), Box::new(Nothing{})]);
+        let iterations = pm.run(&mut ir, &RuleContext::new(&pt, &cfg));
+        assert!(iterations > 1);
+        assert!(!ir.iter().flat_map(|b| b.iter()).any(|e| e.to_string().contains("lit(1)")));
+    }
+
+    #[test]
+    fn test_disable() {
+        let (mut ir, cfg, pt) = setup();
+        let mut pm = PassManager::new(vec![Box::new(CountDown{})]);
+        pm.disable("count_down").unwrap();
+        assert!(pm.run(&mut ir, &RuleContext::new(&pt, &cfg)) == 0);
+        assert!(pm.disable("unknown").is_err());
+    }
+
+    #[test]
+    fn test_reorder_and_max_iterations() {
+        let (mut ir, cfg, pt) = setup();
+        let mut pm = PassManager::new(vec![Box::new(Nothing{}), Box::new(CountDown{})]);
+        pm.reorder(&["count_down"]).unwrap();
+        assert!(pm.rule_names() == vec!["count_down", "nothing"]);
+        pm.set_max_iterations(1);
+        assert!(pm.run(&mut ir, &RuleContext::new(&pt, &cfg)) == 1);
+    }
+}
@@ -0,0 +1,422 @@
+// Conversion of a prototype's IR into and out of static single assignment form.
+//
+// Slots are versioned as SVar(slot, version). Version 0 is the value a slot holds when the
+// prototype is entered (parameters or nothing). Phi nodes are placed at the dominance frontier
+// of every definition, pruned to the slots that are live into the join block.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ir::{
+    cfg::Cfg,
+    dataflow::{Liveness, Slots},
+    ir_gen::Exp,
+    translator::IRPrototype,
+};
+
+pub struct Ssa{}
+impl Ssa {
+    /// Rewrites every slot read and write in ir into versioned slots and inserts phi nodes at join points.
+    pub fn construct(ir: &mut IRPrototype, cfg: &Cfg) {
+        let idom = cfg.dominators();
+        let frontiers = cfg.dominance_frontiers(&idom);
+        let liveness = Liveness::new(ir, cfg);
+
+        //Blocks defining each slot.
+        let mut def_sites: BTreeMap<u16, BTreeSet<usize>> = BTreeMap::new();
+        for block in ir.iter() {
+            for exp in block.iter() {
+                for slot in Slots::defs(exp) {
+                    def_sites.entry(slot).or_default().insert(block.id);
+                }
+            }
+        }
+
+        //Phi placement.
+        let mut phis: Vec<Vec<u16>> = vec![vec![]; cfg.len()];
+        for (slot, sites) in def_sites.iter() {
+            let mut work: Vec<usize> = sites.iter().cloned().collect();
+            let mut placed: BTreeSet<usize> = BTreeSet::new();
+            while let Some(b) = work.pop() {
+                for f in frontiers[b].iter().cloned() {
+                    if placed.contains(&f) || !liveness.live_in[f].contains(slot) { continue; }
+                    placed.insert(f);
+                    phis[f].push(*slot);
+                    if !sites.contains(&f) { work.push(f); }
+                }
+            }
+        }
+        for (b, slots) in phis.iter().enumerate() {
+            let block = &mut ir.proto_ir[b];
+            for slot in slots.iter().rev() {
+                let args = vec![Exp::Var(*slot); cfg.preds[b].len()];
+                block.block_ir.insert(0, Exp::Move(Box::new(Exp::Var(*slot)), Box::new(Exp::Phi(args))));
+            }
+        }
+
+        //Renaming over the dominator tree.
+        let mut children: Vec<Vec<usize>> = vec![vec![]; cfg.len()];
+        for (b, d) in idom.iter().enumerate() {
+            if let Some(d) = d {
+                if *d != b { children[*d].push(b); }
+            }
+        }
+        let mut renamer = Renamer {
+            stacks: BTreeMap::new(),
+            next: BTreeMap::new(),
+        };
+        if cfg.len() > 0 {
+            renamer.rename_block(ir, cfg, &children, 0);
+        }
+    }
+
+    /// Returns ir to slot form. Phi operands naming a different slot than the phi are copied at the end of the
+    /// corresponding predecessor, then every versioned slot is replaced by its plain slot.
+    pub fn destruct(ir: &mut IRPrototype, cfg: &Cfg) {
+        let mut copies: Vec<Vec<Exp>> = vec![vec![]; cfg.len()];
+        for block in ir.iter_mut() {
+            let mut kept: Vec<Exp> = vec![];
+            for exp in block.block_ir.drain(..) {
+                match exp {
+                    Exp::Move(dst, src) if matches!(src.as_ref(), Exp::Phi(_)) => {
+                        let slot = Slots::range(&dst)[0];
+                        if let Exp::Phi(args) = *src {
+                            for (i, arg) in args.into_iter().enumerate() {
+                                if Slots::range(&arg) != vec![slot] {
+                                    copies[cfg.preds[block.id][i]].push(Exp::Move(Box::new(Exp::Var(slot)), Box::new(arg)));
+                                }
+                            }
+                        }
+                    },
+                    _ => kept.push(exp),
+                }
+            }
+            block.block_ir = kept;
+        }
+
+        for (b, block_copies) in copies.into_iter().enumerate() {
+            let block = &mut ir.proto_ir[b];
+            let at = Ssa::terminator_index(&block.block_ir);
+            for (i, copy) in block_copies.into_iter().enumerate() {
+                block.block_ir.insert(at + i, copy);
+            }
+        }
+
+        for block in ir.iter_mut() {
+            for exp in block.iter_mut() {
+                Ssa::unversion(exp);
+                Ssa::restore_ranges(exp);
+            }
+        }
+    }
+
+    /// Renaming turns the slot ranges of calls and varargs into Lists of single slots. Puts back the Range (or
+    /// Empty) the translator built, keeping a trailing MultRes as the open end.
+    fn restore_ranges(exp: &mut Exp) {
+        match exp {
+            Exp::Call(_, params, returns) => {
+                Ssa::slot_range(params);
+                Ssa::slot_range(returns);
+            },
+            Exp::VarArg(range) => Ssa::slot_range(range),
+            _ => (),
+        }
+        for child in exp.children_mut() {
+            Ssa::restore_ranges(child);
+        }
+    }
+
+    fn slot_range(exp: &mut Exp) {
+        let items = match exp {
+            Exp::List(items) => items,
+            _ => return,
+        };
+        let open = match items.last() {
+            Some(Exp::MultRes(_)) => items.pop(),
+            _ => None,
+        };
+        let slots: Vec<u16> = items.iter().filter_map(|e| match e { Exp::Var(s) => Some(*s), _ => None }).collect();
+        let consecutive = slots.len() == items.len() && slots.windows(2).all(|w| w[1] == w[0] + 1);
+        if consecutive && !slots.is_empty() {
+            *items = vec![Exp::Range(slots[0] as u32, slots[slots.len() - 1] as u32)];
+        }
+        items.extend(open);
+        if items.len() == 1 && !matches!(items[0], Exp::MultRes(_)) {
+            *exp = items.remove(0);
+        } else if items.is_empty() {
+            *exp = Exp::Empty;
+        }
+    }
+
+    /// Index of the first expression of the control flow at the end of a block.
+    pub(crate) fn terminator_index(exps: &[Exp]) -> usize {
+        let mut at = exps.len();
+        while at > 0 {
+            match &exps[at - 1] {
+                Exp::Jump(_) | Exp::Goto(_) | Exp::Comparison(_, _, _) | Exp::UClo(_, _) |
+                Exp::Return(_) | Exp::For(_, _, _, _, _) | Exp::IterFor(_, _, _) |
+                Exp::Redundant(_) => at -= 1,
+                Exp::IsT(mv, _) if matches!(mv.as_ref(), Exp::Empty) => at -= 1,
+                Exp::Not(v) if matches!(v.as_ref(), Exp::Comparison(_, _, _)) => at -= 1,
+                _ => break,
+            }
+        }
+        at
+    }
+
+    fn unversion(exp: &mut Exp) {
+        if let Exp::SVar(slot, _) = exp {
+            *exp = Exp::Var(*slot);
+            return;
+        }
+        for child in exp.children_mut() {
+            Ssa::unversion(child);
+        }
+    }
+
+    /// Number of phi nodes in ir.
+    pub fn phi_count(ir: &IRPrototype) -> usize {
+        ir.iter()
+            .flat_map(|b| b.iter())
+            .filter(|e| matches!(e, Exp::Move(_, src) if matches!(src.as_ref(), Exp::Phi(_))))
+            .count()
+    }
+}
+
+struct Renamer {
+    stacks: BTreeMap<u16, Vec<u32>>,
+    next: BTreeMap<u16, u32>,
+}
+
+impl Renamer {
+    fn top(&self, slot: u16) -> u32 {
+        self.stacks.get(&slot).and_then(|s| s.last().cloned()).unwrap_or(0)
+    }
+
+    fn push(&mut self, slot: u16) -> u32 {
+        let version = self.next.entry(slot).or_insert(0);
+        *version += 1;
+        let version = *version;
+        self.stacks.entry(slot).or_default().push(version);
+        version
+    }
+
+    fn rename_block(&mut self, ir: &mut IRPrototype, cfg: &Cfg, children: &[Vec<usize>], b: usize) {
+        let mut pushed: Vec<u16> = vec![];
+        for exp in ir.proto_ir[b].iter_mut() {
+            let is_phi = matches!(exp, Exp::Move(_, src) if matches!(src.as_ref(), Exp::Phi(_)));
+            if !is_phi {
+                self.rename_uses(exp);
+            }
+            self.rename_defs(exp, &mut pushed);
+        }
+
+        for succ in cfg.succs[b].iter().cloned() {
+            let j = cfg.preds[succ].iter().position(|p| *p == b).unwrap();
+            for exp in ir.proto_ir[succ].iter_mut() {
+                if let Exp::Move(dst, src) = exp {
+                    if let Exp::Phi(args) = src.as_mut() {
+                        let slot = Slots::range(dst)[0];
+                        args[j] = Exp::SVar(slot, self.top(slot));
+                    }
+                }
+            }
+        }
+
+        for child in children[b].iter().cloned() {
+            self.rename_block(ir, cfg, children, child);
+        }
+        for slot in pushed {
+            self.stacks.get_mut(&slot).unwrap().pop();
+        }
+    }
+
+    fn rename_uses(&self, exp: &mut Exp) {
+        match exp {
+            Exp::Var(slot) => *exp = Exp::SVar(*slot, self.top(*slot)),
+            Exp::Move(dst, src) => {
+                if !Slots::is_slot_target(dst) {
+                    self.rename_uses(dst);
+                }
+                self.rename_uses(src);
+            },
+            Exp::Local(_, src) => self.rename_uses(src),
+            Exp::Call(f, params, _) => {
+                self.rename_uses(f);
+                self.rename_slot_list(params);
+            },
+            Exp::Return(v) => match v.as_ref() {
+                Exp::Range(_, _) | Exp::List(_) => self.rename_slot_list(v),
+                _ => self.rename_uses(v),
+            },
+            Exp::IsT(mv, d) => {
+                if let Exp::Move(_, src) = mv.as_mut() {
+                    self.rename_uses(src);
+                }
+                self.rename_uses(d);
+            },
+            Exp::For(_, start, stop, step, _) => {
+                self.rename_uses(start);
+                self.rename_uses(stop);
+                self.rename_uses(step);
+            },
+            Exp::IterFor(_, explist, _) => self.rename_uses(explist),
+            Exp::VarArg(_) | Exp::Do(_) => (),
+            _ => {
+                for child in exp.children_mut() {
+                    self.rename_uses(child);
+                }
+            },
+        }
+    }
+
+    /// Replaces a slot Range by the List of the current versions of its slots. Lists are flattened, keeping
+    /// MultRes as the open end.
+    fn rename_slot_list(&self, exp: &mut Exp) {
+        match exp {
+            Exp::Range(_, _) => {
+                let slots = Slots::range(exp);
+                *exp = Exp::List(slots.into_iter().map(|s| Exp::SVar(s, self.top(s))).collect());
+            },
+            Exp::List(items) => {
+                let mut flat: Vec<Exp> = vec![];
+                for mut item in items.drain(..) {
+                    self.rename_slot_list(&mut item);
+                    match item {
+                        Exp::List(inner) => flat.extend(inner),
+                        other => flat.push(other),
+                    }
+                }
+                *items = flat;
+            },
+            Exp::MultRes(_) => (),
+            _ => self.rename_uses(exp),
+        }
+    }
+
+    fn rename_defs(&mut self, exp: &mut Exp, pushed: &mut Vec<u16>) {
+        let target = match exp {
+            Exp::Move(dst, _) if Slots::is_slot_target(dst) => Some(dst),
+            Exp::Local(dst, _) => Some(dst),
+            Exp::Call(_, _, returns) => Some(returns),
+            Exp::VarArg(range) => Some(range),
+            Exp::IsT(mv, _) => match mv.as_mut() {
+                Exp::Move(dst, _) => Some(dst),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match target {
+            Some(target) if matches!(target.as_ref(), Exp::Empty | Exp::MultRes(_)) => {
+                //No fixed results. Open results are only read through MultRes.
+                for slot in Slots::range(target) {
+                    self.push(slot);
+                    pushed.push(slot);
+                }
+            },
+            Some(target) => {
+                let slots = Slots::range(target);
+                let mut versions: Vec<Exp> = vec![];
+                for slot in slots.iter().cloned() {
+                    versions.push(Exp::SVar(slot, self.push(slot)));
+                    pushed.push(slot);
+                }
+                **target = match target.as_ref() {
+                    Exp::Var(_) | Exp::SVar(_, _) => versions.pop().unwrap(),
+                    _ => Exp::List(versions),
+                };
+            },
+            None => {
+                //Implicit definitions such as the visible variable of a numeric for loop.
+                for slot in Slots::defs(exp) {
+                    self.push(slot);
+                    pushed.push(slot);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        translator::Translator,
+    };
+    use super::*;
+
+    fn setup() -> (IRPrototype, Cfg) {
+        let mut ptr = Prototyper::new("dec.lua");
+        ptr.next().unwrap(); //dec.ifs
+        let pt = ptr.next().unwrap(); //dec.loops
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        (Translator{}.translate_blocks(blocks), cfg)
+    }
+
+    fn has_svar(exp: &Exp) -> bool {
+        matches!(exp, Exp::SVar(_, _) | Exp::Phi(_)) || exp.children().into_iter().any(has_svar)
+    }
+
+    #[test]
+    fn test_construct() {
+        let (mut ir, cfg) = setup();
+        Ssa::construct(&mut ir, &cfg);
+        //The while loop header merges the counter from before the loop and from the loop body.
+        assert!(Ssa::phi_count(&ir) > 0);
+        let header = &ir.proto_ir[1].block_ir[0];
+        assert!(header.to_string().starts_with("var(0)_"), "found: {}", header);
+        assert!(matches!(header, Exp::Move(_, src) if matches!(src.as_ref(), Exp::Phi(args) if args.len() == 2)));
+
+        //Every definition of a slot gets a distinct version.
+        let mut seen: BTreeSet<(u16, u32)> = BTreeSet::new();
+        for block in ir.iter() {
+            for exp in block.iter() {
+                if let Exp::Move(dst, _) = exp {
+                    if let Exp::SVar(slot, version) = dst.as_ref() {
+                        assert!(seen.insert((*slot, *version)), "redefined: {}", exp);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_destruct() {
+        let (mut ir, cfg) = setup();
+        let before: Vec<usize> = ir.iter().map(|b| b.block_ir.len()).collect();
+        Ssa::construct(&mut ir, &cfg);
+        Ssa::destruct(&mut ir, &cfg);
+        let after: Vec<usize> = ir.iter().map(|b| b.block_ir.len()).collect();
+        assert!(before == after);
+        assert!(!ir.iter().flat_map(|b| b.iter()).any(has_svar));
+    }
+
+    #[test]
+    fn test_destruct_copies() {
+        let (mut ir, cfg) = setup();
+        Ssa::construct(&mut ir, &cfg);
+        //The loop counter merged from two other slots needs a copy in each predecessor.
+        if let Exp::Move(_, src) = &mut ir.proto_ir[1].block_ir[0] {
+            **src = Exp::Phi(vec![Exp::SVar(5, 1), Exp::SVar(6, 1)]);
+        }
+        Ssa::destruct(&mut ir, &cfg);
+        assert!(!ir.proto_ir[1].iter().any(|e| matches!(e, Exp::Move(_, src) if matches!(src.as_ref(), Exp::Phi(_)))));
+        for (pred, slot) in cfg.preds[1].iter().zip([5, 6]) {
+            let exps = &ir.proto_ir[*pred].block_ir;
+            let copy = &exps[Ssa::terminator_index(exps) - 1];
+            assert_eq!(copy.to_string(), format!("var(0) := var({})", slot));
+        }
+    }
+
+    #[test]
+    fn test_round_trip_keeps_ranges() {
+        let (mut ir, cfg) = setup();
+        let before: Vec<String> = ir.iter().flat_map(|b| b.iter()).map(|e| e.to_string()).collect();
+        Ssa::construct(&mut ir, &cfg);
+        Ssa::destruct(&mut ir, &cfg);
+        let after: Vec<String> = ir.iter().flat_map(|b| b.iter()).map(|e| e.to_string()).collect();
+        assert_eq!(before, after);
+    }
+}
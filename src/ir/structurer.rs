@@ -0,0 +1,1448 @@
+// Structures the basic blocks of a prototype into nested statements. Short-circuit evaluations are merged
+// into and/or expressions and compound conditions first, then forward branches become if statements.
+
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
+};
+
+use crate::{
+    dis::{
+        lua_table::LuaValue,
+        prototyper::Prototype,
+    },
+    ir::{
+        cfg::Cfg,
+        dataflow::{Liveness, Slots},
+        ir_gen::Exp,
+        rules::{ProtoSummary, RuleContext},
+        translator::IRPrototype,
+    },
+};
+
+/// How control leaves a node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exit {
+    Fall,               //continues with the next node.
+    Jump(usize),        //unconditional jump to a node.
+    Branch(Exp, usize), //jumps to a node when the condition holds, continues with the next node otherwise.
+    Stop,               //returns from the prototype.
+    ForPrep(Exp, usize), //FORI: enters the loop body that follows or skips to the node after the loop.
+    IterPrep(Exp, usize), //ISNEXT or ITERJ: jumps to the node calling the iterator, after the loop body.
+    Latch(usize),       //FORL or ITERL: loops back to the first node of the body or leaves the loop.
+    Raw(Vec<usize>),    //successors of a transfer that is not structured. The statements keep its expressions.
+}
+
+/// A basic block being structured: its statements without the jump ending it.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: usize,
+    pub start_index: usize,
+    pub stmts: Vec<Exp>,
+    pub exit: Exit,
+}
+
+pub struct Structurer<'a> {
+    nodes: Vec<Node>,
+    pt: &'a Prototype,
+    enclosing: Cell<Option<(usize, usize)>>, //header and exit of the innermost loop being structured.
+    labels: BTreeSet<String>, //labels the gotos left after structuring go to.
+    liveness: Liveness, //of the IR the nodes were made from, merging conditions only drops dead writes.
+}
+
+impl<'a> Structurer<'a> {
+    /// Structures the IR of a prototype into its statements.
+    pub fn structure(ir: IRPrototype, cfg: &Cfg, pt: &Prototype) -> Vec<Exp> {
+        let mut s = Structurer::new(ir, cfg, pt);
+        s.merge_conditions();
+        let len = s.nodes.len();
+        let stmts = s.statements(0, len, len);
+
+        //The gotos are only known once structured. Structuring again places the labels they go to.
+        stmts.iter().for_each(|stmt| Structurer::gotos(stmt, &mut s.labels));
+        let mut stmts = if s.labels.is_empty() { stmts } else { s.statements(0, len, len) };
+        Structurer::scopes(&mut stmts);
+        Structurer::label_scopes(&mut stmts);
+        Structurer::close_returns(&mut stmts);
+        stmts
+    }
+
+    /// Ends the scope of the locals from slot a at each UCLO a. The locals declared from there on in the same
+    /// block go in a do block, unless the block ends there anyway.
+    fn scopes(stmts: &mut Vec<Exp>) {
+        for stmt in stmts.iter_mut() {
+            match stmt {
+                Exp::If(_, then, otherwise) => {
+                    Structurer::scopes(then);
+                    Structurer::scopes(otherwise);
+                },
+                Exp::While(_, body) | Exp::Repeat(body, _) | Exp::For(_, _, _, _, body) | Exp::IterFor(_, _, body) |
+                Exp::Do(body) => Structurer::scopes(body),
+                _ => (),
+            }
+        }
+        let mut i = 0;
+        while i < stmts.len() {
+            let a = match stmts[i] {
+                Exp::UClo(a, _) => a,
+                _ => {
+                    i += 1;
+                    continue;
+                },
+            };
+            stmts.remove(i);
+            let is_end = stmts[i..].iter().all(|s| matches!(s, Exp::Break | Exp::GotoLabel(_) | Exp::Return(_)));
+            let declared = stmts[..i].iter().position(|s| matches!(s, Exp::Local(_, _)) && Slots::defs(s).iter().any(|x| *x >= a));
+            if let (false, Some(j)) = (is_end, declared) {
+                let body: Vec<Exp> = stmts.drain(j..i).collect();
+                stmts.insert(j, Exp::Do(body));
+                i = j + 1;
+            }
+        }
+    }
+
+    /// A goto may not jump into the scope of a local. The locals declared between a goto and the label after it
+    /// go in a do block ending before the label when they are not read from the label on. Otherwise they are
+    /// declared before the goto and assigned where they were declared. A label ending the block is out of scope.
+    fn label_scopes(stmts: &mut Vec<Exp>) {
+        for stmt in stmts.iter_mut() {
+            match stmt {
+                Exp::If(_, then, otherwise) => {
+                    Structurer::label_scopes(then);
+                    Structurer::label_scopes(otherwise);
+                },
+                Exp::While(_, body) | Exp::Repeat(body, _) | Exp::For(_, _, _, _, body) | Exp::IterFor(_, _, body) |
+                Exp::Do(body) => Structurer::label_scopes(body),
+                _ => (),
+            }
+        }
+        let mut p = 0;
+        while p < stmts.len() {
+            let label = match &stmts[p] {
+                Exp::Label(label) if !stmts[p + 1..].iter().all(|s| matches!(s, Exp::Label(_))) => label.clone(),
+                _ => {
+                    p += 1;
+                    continue;
+                },
+            };
+            let goto = (0..p).find(|g| {
+                let mut labels: BTreeSet<String> = BTreeSet::new();
+                Structurer::gotos(&stmts[*g], &mut labels);
+                labels.contains(&label)
+            });
+            let locals: Vec<usize> = match goto {
+                Some(g) => (g + 1..p).filter(|j| matches!(stmts[*j], Exp::Local(_, _))).collect(),
+                None => vec![],
+            };
+            let (g, first) = match (goto, locals.first()) {
+                (Some(g), Some(first)) => (g, *first),
+                _ => {
+                    p += 1;
+                    continue;
+                },
+            };
+
+            let dsts: Vec<Exp> = locals.iter().filter_map(|j| match &stmts[*j] {
+                Exp::Local(dst, _) => Some(dst.as_ref().clone()),
+                _ => None,
+            }).collect();
+            let read_after = stmts[p..].iter().any(|s| dsts.iter().any(|d| Structurer::reads(s, d) > 0));
+            //Labels inside the do block could no longer be reached from outside it.
+            let has_labels = stmts[first..p].iter().any(|s| matches!(s, Exp::Label(_)));
+            if !read_after && !has_labels {
+                let body: Vec<Exp> = stmts.drain(first..p).collect();
+                stmts.insert(first, Exp::Do(body));
+                p = first + 2;
+                continue;
+            }
+            //A local declared without a value keeps the nil of its new declaration.
+            for j in locals.iter().rev() {
+                match stmts.remove(*j) {
+                    Exp::Local(_, value) if Exp::is_nil(&value) => p -= 1,
+                    Exp::Local(dst, value) => stmts.insert(*j, Exp::Move(dst, value)),
+                    _ => unreachable!(),
+                }
+            }
+            for (k, dst) in dsts.into_iter().enumerate() {
+                stmts.insert(g + k, Exp::Local(Box::new(dst), Box::new(Exp::Pri(0))));
+            }
+            p += locals.len() + 1;
+        }
+    }
+
+    /// Puts the functions of the child prototypes structured so far where their closures are created.
+    pub fn inline_closures(stmts: &mut [Exp], ctx: &RuleContext) {
+        for stmt in stmts.iter_mut() {
+            stmt.rewrite(&mut |e| match e {
+                Exp::Func(index, info) if **info == Exp::Empty => match ctx.child(*index) {
+                    Some(child) => {
+                        **info = Structurer::function(child);
+                        true
+                    },
+                    None => false,
+                },
+                _ => false,
+            });
+        }
+    }
+
+    /// function(params) body end of a child prototype. Named parameters are renamed throughout the body,
+    /// but not in the functions nested in it, which have slots of their own.
+    fn function(child: &ProtoSummary) -> Exp {
+        fn rename(exp: &mut Exp, param: &Exp) {
+            match exp {
+                Exp::Func(_, _) => (),
+                Exp::Var(slot) if matches!(param, Exp::Named(s, _) if s == slot) => *exp = param.clone(),
+                _ => exp.children_mut().into_iter().for_each(|child| rename(child, param)),
+            }
+        }
+        let mut params: Vec<Exp> = (0..child.num_params as u16).map(|slot| match child.params.get(slot as usize) {
+            Some(name) => Exp::Named(slot, name.clone()),
+            None => Exp::Var(slot),
+        }).collect();
+        let mut body = child.body.clone();
+        for param in params.iter().filter(|p| matches!(p, Exp::Named(_, _))) {
+            body.iter_mut().for_each(|stmt| rename(stmt, param));
+        }
+        if child.is_vararg {
+            params.push(Exp::VarArg(Box::new(Exp::Empty)));
+        }
+        Exp::Function(Box::new(Exp::List(params)), body)
+    }
+
+    /// Returns followed by a label or unreachable code, which Lua only accepts last in a block, go in a do block.
+    fn close_returns(stmts: &mut [Exp]) {
+        let last = stmts.len().saturating_sub(1);
+        for (i, stmt) in stmts.iter_mut().enumerate() {
+            match stmt {
+                Exp::Return(_) if i < last => *stmt = Exp::Do(vec![stmt.clone()]),
+                Exp::If(_, then, otherwise) => {
+                    Structurer::close_returns(then);
+                    Structurer::close_returns(otherwise);
+                },
+                Exp::While(_, body) | Exp::Repeat(body, _) | Exp::For(_, _, _, _, body) | Exp::IterFor(_, _, body) |
+                Exp::Do(body) => Structurer::close_returns(body),
+                _ => (),
+            }
+        }
+    }
+
+    fn new(ir: IRPrototype, cfg: &Cfg, pt: &'a Prototype) -> Structurer<'a> {
+        let starts: HashMap<usize, usize> = ir.iter().map(|b| (b.start_index, b.id)).collect();
+        let len = ir.proto_ir.len();
+        let reachable = Structurer::reachable(cfg, len);
+        let liveness = Liveness::new(&ir, cfg);
+        let nodes = ir.proto_ir.into_iter().map(|block| {
+            let mut stmts = block.block_ir;
+            let next = if block.id + 1 < len { vec![block.id + 1] } else { vec![] };
+            let mut exit = Structurer::exit(&mut stmts, &starts, &cfg.succs[block.id], &next);
+            //The jump after a return is never taken. Without it, the return ends the then part it is in.
+            if !reachable[block.id] && stmts.is_empty() && matches!(exit, Exit::Jump(_)) {
+                exit = Exit::Fall;
+            }
+            Node {
+                id: block.id,
+                start_index: block.start_index,
+                stmts: stmts,
+                exit: exit,
+            }
+        }).collect();
+        Structurer {
+            nodes: nodes,
+            pt: pt,
+            enclosing: Cell::new(None),
+            labels: BTreeSet::new(),
+            liveness: liveness,
+        }
+    }
+
+    fn reachable(cfg: &Cfg, len: usize) -> Vec<bool> {
+        let mut reachable = vec![false; len];
+        let mut work = vec![0];
+        while let Some(n) = work.pop() {
+            if n >= len || reachable[n] { continue; }
+            reachable[n] = true;
+            work.extend(cfg.succs[n].iter().cloned());
+        }
+        reachable
+    }
+
+    /// Takes the jump ending a block, and the test guarding it, off its statements.
+    fn exit(stmts: &mut Vec<Exp>, starts: &HashMap<usize, usize>, succs: &[usize], next: &[usize]) -> Exit {
+        let target = match stmts.last() {
+            Some(Exp::Jump(t)) | Some(Exp::Goto(t)) => starts.get(&(*t as usize)).cloned(),
+            Some(Exp::UClo(_, j)) => match j.as_ref() {
+                Exp::Jump(t) => starts.get(&(*t as usize)).cloned(),
+                _ => None,
+            },
+            Some(Exp::Return(_)) => return Exit::Stop,
+            Some(Exp::For(_, _, _, _, _)) if succs.len() == 2 => return Exit::ForPrep(stmts.pop().unwrap(), succs[0]),
+            Some(Exp::IterFor(_, _, _)) if succs.len() == 1 => return Exit::IterPrep(stmts.pop().unwrap(), succs[0]),
+            Some(Exp::Redundant(op)) if (op == "FORL" || op == "ITERL") && succs.len() == 2 => {
+                stmts.pop();
+                return Exit::Latch(succs[0]);
+            },
+            _ => None,
+        };
+        match target {
+            Some(t) => {
+                //A UCLO leaving a scope stays behind without its jump, it marks where the scope ends.
+                if let Some(Exp::UClo(a, _)) = stmts.pop() {
+                    stmts.push(Exp::UClo(a, Box::new(Exp::Empty)));
+                    return Exit::Jump(t);
+                }
+                match stmts.last() {
+                    Some(e) if Structurer::is_test(e) => Exit::Branch(stmts.pop().unwrap(), t),
+                    _ => Exit::Jump(t),
+                }
+            },
+            None if succs == next => Exit::Fall,
+            None => Exit::Raw(succs.to_vec()),
+        }
+    }
+
+    /// True for the translation of a comparison or unary test, which makes the jump after it conditional.
+    fn is_test(exp: &Exp) -> bool {
+        match exp {
+            Exp::Comparison(_, _, _) | Exp::IsT(_, _) => true,
+            Exp::Not(v) => matches!(v.as_ref(), Exp::Comparison(_, _, _)),
+            _ => false,
+        }
+    }
+
+    /// True for the LOOP marking the start of a while or repeat loop. It keeps the node it starts apart from
+    /// the nodes before it, so that nested loops keep their own headers, but is not a statement.
+    fn is_loop_marker(exp: &Exp) -> bool {
+        matches!(exp, Exp::Redundant(op) if op == "LOOP")
+    }
+
+    fn is_empty(&self, n: usize) -> bool {
+        self.nodes[n].stmts.is_empty() && self.nodes[n].exit == Exit::Fall
+    }
+
+    /// First node from n on that is not an empty node falling through. Merged nodes are left that way.
+    fn resolve(&self, mut n: usize) -> usize {
+        while n < self.nodes.len() && self.is_empty(n) {
+            n += 1;
+        }
+        n
+    }
+
+    /// Resolved successors of a node, the taken edge of a branch first.
+    fn succs(&self, n: usize) -> Vec<usize> {
+        let succs = match &self.nodes[n].exit {
+            Exit::Fall         => vec![n + 1],
+            Exit::Jump(t)      => vec![*t],
+            Exit::Branch(_, t) => vec![*t, n + 1],
+            Exit::Stop         => vec![],
+            Exit::ForPrep(_, x) => vec![*x, n + 1],
+            Exit::IterPrep(_, c) => vec![*c],
+            Exit::Latch(t)   => vec![*t, n + 1],
+            Exit::Raw(succs)   => succs.clone(),
+        };
+        succs.into_iter().map(|s| self.resolve(s)).filter(|s| *s < self.nodes.len()).collect()
+    }
+
+    /// Nodes that are not empty and lead to node n.
+    fn preds(&self, n: usize) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|p| !self.is_empty(*p) && self.succs(*p).contains(&n)).collect()
+    }
+
+    /// The condition a branch tests. Tests copying their operand are only conditions as part of an and/or.
+    fn condition(test: &Exp) -> Option<Exp> {
+        match test {
+            Exp::IsT(copy, d) if **copy == Exp::Empty => Some(d.as_ref().clone()),
+            Exp::IsT(_, _) => None,
+            cond => Some(cond.clone()),
+        }
+    }
+
+    /// Slot a unary test is about, the value it copies into it and whether it jumps when the value is truthy.
+    fn test(test: &Exp) -> Option<(u16, Option<Exp>, bool)> {
+        match test {
+            Exp::IsT(copy, d) => {
+                let truthy = !matches!(d.as_ref(), Exp::Not(_));
+                match (copy.as_ref(), d.as_ref()) {
+                    (Exp::Move(dst, src), _) => Slots::slot(dst).map(|x| (x, Some(src.as_ref().clone()), truthy)),
+                    (Exp::Empty, Exp::Not(v)) => Slots::slot(v).map(|x| (x, None, truthy)),
+                    (Exp::Empty, v) => Slots::slot(v).map(|x| (x, None, truthy)),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// Destination and value of the only statement of node n when it assigns slot x, and whether it declares x.
+    fn assignment(&self, n: usize, x: u16) -> Option<(Exp, Exp, bool)> {
+        match self.nodes[n].stmts.as_slice() {
+            [Exp::Move(dst, v)] if Slots::slot(dst) == Some(x) => Some((dst.as_ref().clone(), v.as_ref().clone(), false)),
+            [Exp::Local(dst, v)] if Slots::slot(dst) == Some(x) => Some((dst.as_ref().clone(), v.as_ref().clone(), true)),
+            _ => None,
+        }
+    }
+
+    fn assign(dst: Exp, value: Exp, local: bool) -> Exp {
+        if local {
+            Exp::Local(Box::new(dst), Box::new(value))
+        } else {
+            Exp::Move(Box::new(dst), Box::new(value))
+        }
+    }
+
+    /// Destination and value of the last of stmts when it writes slot x, taken off stmts, and whether it declares x.
+    /// A call returning into x is its own value, its results are left out.
+    fn take_value(stmts: &mut Vec<Exp>, x: u16) -> Option<(Exp, Exp, bool)> {
+        let taken = match stmts.last()? {
+            Exp::Move(d, v) if Slots::slot(d) == Some(x) => (d.as_ref().clone(), v.as_ref().clone(), false),
+            Exp::Local(d, v) if Slots::slot(d) == Some(x) => (d.as_ref().clone(), v.as_ref().clone(), true),
+            Exp::Call(f, params, returns) if Slots::range(returns) == [x] => {
+                (Exp::Var(x), Exp::Call(f.clone(), params.clone(), Box::new(Exp::Empty)), false)
+            },
+            _ => return None,
+        };
+        stmts.pop();
+        Some(taken)
+    }
+
+    /// Folds the temporaries written last in stmts of node n into value, which reads each of them once. Earlier
+    /// writes of x are overwritten by value. Returns value with its operands written out.
+    fn fold_temporaries(&self, n: usize, x: u16, stmts: &mut Vec<Exp>, mut value: Exp) -> Option<Exp> {
+        if let Exp::Call(_, params, _) = &mut value {
+            if let Exp::Range(_, _) = params.as_ref() {
+                **params = Exp::List(Slots::range(params).into_iter().map(Exp::Var).collect());
+            }
+        }
+        while let Some(Exp::Move(dst, v)) = stmts.last() {
+            let slot = match dst.as_ref() {
+                Exp::Var(slot) if *slot == x || !self.liveness.live_out[n].contains(slot) => *slot,
+                _ => break,
+            };
+            if Structurer::reads(&value, &Exp::Var(slot)) != 1 { break; }
+            Structurer::substitute(&mut value, &Exp::Var(slot), v);
+            stmts.pop();
+        }
+        Some(value)
+    }
+
+    /// Replaces var by value in exp. A value reading var itself is not searched again.
+    fn substitute(exp: &mut Exp, var: &Exp, value: &Exp) {
+        if exp == var {
+            *exp = value.clone();
+            return;
+        }
+        for child in exp.children_mut() {
+            Structurer::substitute(child, var, value);
+        }
+    }
+
+    /// Number of times exp reads var.
+    fn reads(exp: &Exp, var: &Exp) -> usize {
+        (exp == var) as usize + exp.children().into_iter().map(|e| Structurer::reads(e, var)).sum::<usize>()
+    }
+
+    /// Merges short-circuit evaluations into and/or expressions and compound conditions until nothing changes.
+    /// Later nodes go first so that chains are merged from the inside out.
+    fn merge_conditions(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in (0..self.nodes.len()).rev() {
+                changed |= self.merge_value(n) || self.merge_ternary(n) || self.merge_branch(n);
+            }
+        }
+    }
+
+    /// x = a or b and x = a and b: a test of a skipping the single assignment x = b that follows it.
+    fn merge_value(&mut self, n: usize) -> bool {
+        let (x, copied, truthy, target) = match &self.nodes[n].exit {
+            Exit::Branch(test, t) => match Structurer::test(test) {
+                Some((x, copied, truthy)) => (x, copied, truthy, *t),
+                None => return false,
+            },
+            _ => return false,
+        };
+        let m = self.resolve(n + 1);
+        if m >= self.nodes.len() || self.preds(m) != [n] || self.succs(m) != [self.resolve(target)] { return false; }
+        if !matches!(self.nodes[m].exit, Exit::Fall | Exit::Jump(_)) { return false; }
+        let (mut dst, b, mut local) = match self.assignment(m, x) {
+            Some(assignment) => assignment,
+            None => return false,
+        };
+
+        //Without a copy, the tested value is the one last written into x. Either assignment may name x.
+        let a = match copied {
+            Some(a) => a,
+            None => match Structurer::take_value(&mut self.nodes[n].stmts, x) {
+                Some((d, v, declares)) => {
+                    local |= declares;
+                    if matches!(d, Exp::Named(_, _)) { dst = d; }
+                    v
+                },
+                None => return false,
+            },
+        };
+        let value = if truthy {
+            Exp::Or(Box::new(a), Box::new(b))
+        } else {
+            Exp::And(Box::new(a), Box::new(b))
+        };
+        self.nodes[n].stmts.push(Structurer::assign(dst, value, local));
+        self.nodes[n].exit = Exit::Fall;
+        self.nodes[m].stmts.clear();
+        if self.resolve(m + 1) == self.resolve(target) {
+            self.nodes[m].exit = Exit::Fall;
+        }
+        true
+    }
+
+    /// x = c and a or b: a condition skipping to the assignment x = b past a test of a that skips it too. The
+    /// test either copies a into x or tests the value last written into x, whose computation is folded into a.
+    fn merge_ternary(&mut self, n: usize) -> bool {
+        let (c, t) = match &self.nodes[n].exit {
+            Exit::Branch(test, t) => match Structurer::condition(test) {
+                Some(c) => (c, *t),
+                None => return false,
+            },
+            _ => return false,
+        };
+        let m = self.resolve(n + 1);
+        if m >= self.nodes.len() || self.preds(m) != [n] { return false; }
+        let (x, copied, target) = match &self.nodes[m].exit {
+            Exit::Branch(test, target) => match Structurer::test(test) {
+                Some((x, copied, true)) => (x, copied, *target),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        let mut stmts = self.nodes[m].stmts.clone();
+        let a = match copied {
+            Some(a) => Some(a),
+            None => Structurer::take_value(&mut stmts, x).map(|(_, v, _)| v),
+        };
+        let a = match a.and_then(|a| self.fold_temporaries(m, x, &mut stmts, a)) {
+            Some(a) if stmts.is_empty() => a,
+            _ => return false,
+        };
+        let k = self.resolve(m + 1);
+        if k >= self.nodes.len() || self.resolve(t) != k || self.preds(k) != [n, m] { return false; }
+        if self.succs(k) != [self.resolve(target)] || !matches!(self.nodes[k].exit, Exit::Fall | Exit::Jump(_)) { return false; }
+        let (dst, b, local) = match self.assignment(k, x) {
+            Some(assignment) => assignment,
+            None => return false,
+        };
+
+        let value = Exp::Or(Box::new(Exp::And(Box::new(c.negate()), Box::new(a))), Box::new(b));
+        self.nodes[n].stmts.push(Structurer::assign(dst, value, local));
+        self.nodes[n].exit = Exit::Fall;
+        self.nodes[m].stmts.clear();
+        self.nodes[m].exit = Exit::Fall;
+        self.nodes[k].stmts.clear();
+        if self.resolve(k + 1) == self.resolve(target) {
+            self.nodes[k].exit = Exit::Fall;
+        }
+        true
+    }
+
+    /// Merges a branch with the branch it leads to into one compound condition, when that branch only tests,
+    /// past the temporaries its test reads, and is reached from nowhere else.
+    fn merge_branch(&mut self, n: usize) -> bool {
+        let (c1, t1) = match &self.nodes[n].exit {
+            Exit::Branch(test, t) => match Structurer::condition(test) {
+                Some(c) => (c, *t),
+                None => return false,
+            },
+            _ => return false,
+        };
+        let branch = |s: &Structurer, m: usize| -> Option<(Exp, usize)> {
+            if m >= s.nodes.len() || m <= n || s.preds(m) != [n] { return None; }
+            let (mut c, t) = match &s.nodes[m].exit {
+                Exit::Branch(test, t) => (Structurer::condition(test)?, *t),
+                _ => return None,
+            };
+            let mut stmts = s.nodes[m].stmts.clone();
+            s.inline_temporaries(s.nodes[m].start_index, &mut stmts, &mut c);
+            let inlined = s.nodes[m].stmts.iter().flat_map(Slots::defs);
+            if stmts.is_empty() && !inlined.into_iter().any(|slot| s.liveness.live_out[m].contains(&slot)) { Some((c, t)) } else { None }
+        };
+
+        //The second test is reached when the first one fails.
+        let m = self.resolve(n + 1);
+        if let Some((c2, t2)) = branch(self, m) {
+            let merged = if self.resolve(t1) == self.resolve(t2) {
+                Some(Exp::Or(Box::new(c1.clone()), Box::new(c2))) //both jump to the same place.
+            } else if self.resolve(t1) == self.resolve(m + 1) {
+                Some(Exp::And(Box::new(c1.clone().negate()), Box::new(c2))) //the first one skips the second one.
+            } else {
+                None
+            };
+            if let Some(merged) = merged {
+                self.nodes[n].exit = Exit::Branch(merged, t2);
+                self.nodes[m].stmts.clear();
+                self.nodes[m].exit = Exit::Fall;
+                return true;
+            }
+        }
+
+        //The second test is reached when the first one holds and skips to where the first one falls.
+        let m = self.resolve(t1);
+        if let Some((c2, t2)) = branch(self, m) {
+            if self.resolve(t2) == self.resolve(n + 1) {
+                self.nodes[n].exit = Exit::Branch(Exp::And(Box::new(c1), Box::new(c2.negate())), m + 1);
+                self.nodes[m].stmts.clear();
+                self.nodes[m].exit = Exit::Fall;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs f with the loop headed by node h and left for node x as the innermost loop.
+    fn within<T>(&self, h: usize, x: usize, f: impl FnOnce() -> T) -> T {
+        let outer = self.enclosing.replace(Some((h, x)));
+        let result = f();
+        self.enclosing.set(outer);
+        result
+    }
+
+    /// True when a jump to node t leaves the innermost loop.
+    fn is_break(&self, t: usize) -> bool {
+        matches!(self.enclosing.get(), Some((_, x)) if self.resolve(x) == self.resolve(t))
+    }
+
+    /// True when nodes [from, to) are only entered at from and only left for node next, or by a break.
+    fn is_region(&self, from: usize, to: usize, next: usize) -> bool {
+        let (entry, next) = (self.resolve(from), self.resolve(next));
+        let inside = |s: usize| s >= from && s < to;
+        (0..self.nodes.len()).all(|p| {
+            let succs = self.succs(p);
+            if inside(p) {
+                !matches!(self.nodes[p].exit, Exit::Raw(_)) && succs.iter().all(|s| inside(*s) || *s == next || self.is_break(*s))
+            } else {
+                succs.iter().all(|s| !inside(*s) || *s == entry)
+            }
+        })
+    }
+
+    /// Statements of the nodes [from, to). Control leaving the range continues at node next.
+    fn statements(&self, from: usize, to: usize, next: usize) -> Vec<Exp> {
+        let mut stmts: Vec<Exp> = vec![];
+        let mut n = from;
+        while n < to {
+            let label = Structurer::label(self.nodes[n].start_index);
+            if self.labels.contains(&label) && !matches!(self.enclosing.get(), Some((h, _)) if h == n) {
+                stmts.push(Exp::Label(label));
+            }
+            if let Some((stmt, x)) = self.loop_statement(n, to, next) {
+                stmts.push(stmt);
+                n = x;
+                continue;
+            }
+            stmts.extend(self.nodes[n].stmts.iter().filter(|e| !Structurer::is_loop_marker(e)).cloned());
+            match &self.nodes[n].exit {
+                Exit::Branch(test, t) => match self.if_statement(n, test, *t, to, next) {
+                    Some((stmt, join)) => {
+                        stmts.push(stmt);
+                        n = join;
+                        continue;
+                    },
+                    None => stmts.push(self.goto_if(test.clone(), *t)),
+                },
+                //Jumps to where control continues anyway, such as the jump of a then part over its else part.
+                Exit::Jump(t) if self.resolve(*t) == self.resolve(n + 1) => (),
+                Exit::Jump(t) if self.resolve(n + 1) >= to && self.resolve(*t) == self.resolve(next) => (),
+                Exit::Jump(t) if self.is_break(*t) => stmts.push(Exp::Break),
+                Exit::Jump(t) => stmts.push(Exp::GotoLabel(Structurer::label(self.nodes[*t].start_index))),
+                Exit::ForPrep(marker, x) => match self.numeric_for(n, marker, *x, to, next, &mut stmts) {
+                    Some(stmt) => {
+                        stmts.push(stmt);
+                        n = *x;
+                        continue;
+                    },
+                    None => stmts.push(marker.clone()),
+                },
+                Exit::IterPrep(marker, c) => match self.generic_for(n, marker, *c, to, next, &mut stmts) {
+                    Some(stmt) => {
+                        stmts.push(stmt);
+                        n = c + 1;
+                        continue;
+                    },
+                    None => stmts.push(marker.clone()),
+                },
+                Exit::Latch(_) if n + 1 == to => (), //closes the body of the loop being structured.
+                Exit::Latch(_) => stmts.push(Exp::Redundant("FORL".to_string())),
+                Exit::Fall | Exit::Stop | Exit::Raw(_) => (),
+            }
+            n += 1;
+        }
+        stmts
+    }
+
+    /// The loop headed by node n and the node after it. The latch is the last node jumping back to n. A
+    /// header starting with a LOOP heads a repeat loop testing at its latch or an endless while loop,
+    /// otherwise the header tests the condition of a while loop and the LOOP starts its body.
+    fn loop_statement(&self, n: usize, to: usize, next: usize) -> Option<(Exp, usize)> {
+        if self.is_empty(n) || matches!(self.enclosing.get(), Some((h, _)) if h == n) { return None; }
+        let l = (n..to).filter(|p| !matches!(self.nodes[*p].exit, Exit::Latch(_)) && self.succs(*p).contains(&n)).max()?;
+        let x = l + 1;
+        if x == to && self.resolve(x) != self.resolve(next) { return None; }
+        if !self.within(n, x, || self.is_region(n, x, x)) { return None; }
+
+        let stmt = if self.nodes[n].stmts.first().is_some_and(Structurer::is_loop_marker) {
+            match &self.nodes[l].exit {
+                Exit::Branch(test, _) => self.repeat_loop(n, l, x, test)?,
+                //With upvalues to close, the test jumps to a UCLO looping back, past a jump to the exit.
+                Exit::Jump(_) if l >= n + 2 && self.nodes[l].stmts.iter().all(|e| matches!(e, Exp::UClo(_, _))) && self.nodes[l - 1].stmts.is_empty()
+                    && self.nodes[l - 1].exit == Exit::Jump(x) && matches!(&self.nodes[l - 2].exit, Exit::Branch(_, t) if self.resolve(*t) == l) => {
+                    match &self.nodes[l - 2].exit {
+                        Exit::Branch(test, _) => self.repeat_loop(n, l - 2, x, test)?,
+                        _ => unreachable!(),
+                    }
+                },
+                Exit::Jump(_) => {
+                    let mut body = self.within(n, x, || self.statements(n, x, n));
+                    self.continues(n, &mut body);
+                    Exp::While(Box::new(Exp::Literal(LuaValue::True)), body)
+                },
+                _ => return None,
+            }
+        } else {
+            let cond = match (&self.nodes[n].exit, &self.nodes[l].exit) {
+                (Exit::Branch(test, t), Exit::Jump(_)) if self.resolve(*t) == self.resolve(x) => Structurer::condition(test)?,
+                _ => return None,
+            };
+            let mut body = self.within(n, x, || self.statements(n + 1, x, n));
+            self.continues(n, &mut body);
+            self.while_loop(n, cond, body)
+        };
+        Some((stmt, x))
+    }
+
+    /// repeat ... until c: the loop headed by node n with the test ending node l jumping back while c fails.
+    fn repeat_loop(&self, n: usize, l: usize, x: usize, test: &Exp) -> Option<Exp> {
+        let mut cond = Structurer::condition(test)?;
+        let mut body = self.within(n, x, || self.statements(n, l, l));
+        let mut latch: Vec<Exp> = self.nodes[l].stmts.iter().filter(|e| !Structurer::is_loop_marker(e)).cloned().collect();
+        self.inline_temporaries(self.nodes[l].start_index, &mut latch, &mut cond);
+        if latch.is_empty() {
+            self.continues(l, &mut body);
+        }
+        body.extend(latch);
+        Some(Exp::Repeat(body, Box::new(cond.negate())))
+    }
+
+    /// while c do: the header n leaves the loop when cond holds. Temporaries the header computes for the
+    /// test go into the condition, other statements keep the test in the body as an if breaking out.
+    fn while_loop(&self, n: usize, cond: Exp, body: Vec<Exp>) -> Exp {
+        let mut header = self.nodes[n].stmts.clone();
+        let mut inlined = cond.clone();
+        self.inline_temporaries(self.nodes[n].start_index, &mut header, &mut inlined);
+        if header.is_empty() {
+            return Exp::While(Box::new(inlined.negate()), body);
+        }
+        let mut stmts = self.nodes[n].stmts.clone();
+        stmts.push(Exp::If(Box::new(cond), vec![Exp::Break], vec![]));
+        stmts.extend(body);
+        Exp::While(Box::new(Exp::Literal(LuaValue::True)), stmts)
+    }
+
+    /// Moves the values of the temporaries written last in stmts, which are only there for the test of a loop,
+    /// into its condition. Slots without a local name at index are temporaries.
+    fn inline_temporaries(&self, index: usize, stmts: &mut Vec<Exp>, cond: &mut Exp) {
+        while let Some(stmt @ Exp::Move(dst, v)) = stmts.last() {
+            let is_temporary = match dst.as_ref() {
+                Exp::Var(slot) => self.pt.slot_name(*slot, index).is_none() && !Slots::uses(stmt).contains(slot),
+                _ => false,
+            };
+            let (dst, v) = (dst.as_ref().clone(), v.as_ref().clone());
+            if !is_temporary || !cond.rewrite(&mut |e| {
+                if *e != dst { return false; }
+                *e = v.clone();
+                true
+            }) {
+                break;
+            }
+            stmts.pop();
+        }
+    }
+
+    /// Gotos to node n, where the next iteration of the loop with the given body starts, continue with
+    /// that iteration. Those outside the loops nested in the body go to the ::continue:: label ending it.
+    fn continues(&self, n: usize, body: &mut Vec<Exp>) {
+        fn rename(stmts: &mut [Exp], label: &str) -> bool {
+            let mut renamed = false;
+            for stmt in stmts.iter_mut() {
+                match stmt {
+                    Exp::GotoLabel(name) if name == label => {
+                        *name = "continue".to_string();
+                        renamed = true;
+                    },
+                    Exp::If(_, then, otherwise) => renamed |= rename(then, label) | rename(otherwise, label),
+                    Exp::Do(block) => renamed |= rename(block, label),
+                    _ => (),
+                }
+            }
+            renamed
+        }
+        if rename(body, &Structurer::label(self.nodes[n].start_index)) {
+            body.push(Exp::Label("continue".to_string()));
+        }
+    }
+
+    fn label(index: usize) -> String {
+        format!("label_{}", index)
+    }
+
+    /// Labels the gotos in exp go to.
+    fn gotos(exp: &Exp, labels: &mut BTreeSet<String>) {
+        match exp {
+            Exp::GotoLabel(name) if name != "continue" => {
+                labels.insert(name.clone());
+            },
+            _ => exp.children().into_iter().for_each(|child| Structurer::gotos(child, labels)),
+        }
+    }
+
+    /// The if statement of the branch ending node n and the node it joins at. The branch skips the then part,
+    /// which jumps over the else part when there is one.
+    fn if_statement(&self, n: usize, test: &Exp, t: usize, to: usize, next: usize) -> Option<(Exp, usize)> {
+        let cond = Structurer::condition(test)?;
+        if t <= n || t > to || (t == to && self.resolve(t) != self.resolve(next)) { return None; }
+
+        let otherwise = match self.nodes[t - 1].exit {
+            Exit::Jump(e) if t > n + 1 && e > t && (e < to || self.resolve(e) == self.resolve(next)) => Some(e.min(to)),
+            _ => None,
+        };
+        //A part reached by another jump too would need a goto into its block.
+        let is_part = |from: usize, to: usize| from == to || self.preds(self.resolve(from)) == [n];
+        if !is_part(n + 1, t) { return None; }
+        match otherwise {
+            Some(join) if is_part(t, join) && self.is_region(n + 1, t, join) && self.is_region(t, join, join) => {
+                let then = self.statements(n + 1, t, join);
+                let otherwise = self.statements(t, join, join);
+                Some((Exp::If(Box::new(cond.negate()), then, otherwise), join))
+            },
+            _ if self.is_region(n + 1, t, t) => {
+                let then = self.statements(n + 1, t, t);
+                Some((Exp::If(Box::new(cond.negate()), then, vec![]), t))
+            },
+            _ => None,
+        }
+    }
+
+    /// The numeric for loop opened by the FORI ending node n. Its body runs up to the FORL right before the
+    /// exit x. The start, stop and step are the values last written into their slots, which are taken off
+    /// the end of stmts.
+    fn numeric_for(&self, n: usize, marker: &Exp, x: usize, to: usize, next: usize, stmts: &mut Vec<Exp>) -> Option<Exp> {
+        let (var, slots) = match marker {
+            Exp::For(var, start, stop, step, _) => (var.as_ref(), [start.as_ref(), stop.as_ref(), step.as_ref()]),
+            _ => return None,
+        };
+        if x <= n + 1 || x > to || (x == to && self.resolve(x) != self.resolve(next)) { return None; }
+        if self.nodes[x - 1].exit != Exit::Latch(n + 1) || !self.within(n, x, || self.is_region(n + 1, x, x)) { return None; }
+
+        let mut values = self.initializers(self.nodes[n].start_index, &slots, stmts).into_iter();
+        let (start, stop, step) = (values.next()?, values.next()?, values.next()?);
+        let step = match step {
+            Exp::Lit(1) | Exp::Literal(LuaValue::SInt(1)) => Exp::Empty,
+            Exp::Literal(LuaValue::Double(1.0)) => Exp::Empty,
+            step => step,
+        };
+
+        let mut body = self.within(n, x, || self.statements(n + 1, x, x));
+        self.continues(x - 1, &mut body);
+        let var = self.name(var, self.nodes[n + 1].start_index, &mut body);
+        Some(Exp::For(Box::new(var), Box::new(start), Box::new(stop), Box::new(step), body))
+    }
+
+    /// The generic for loop opened by the ISNEXT or ITERJ ending node n. Its body runs up to node c, which
+    /// calls the iterator and loops back. The loop variables are the results of that call and the explist
+    /// the values last written into the generator, state and control slots.
+    fn generic_for(&self, n: usize, marker: &Exp, c: usize, to: usize, next: usize, stmts: &mut Vec<Exp>) -> Option<Exp> {
+        let slots: Vec<&Exp> = match marker {
+            Exp::IterFor(_, explist, _) => match explist.as_ref() {
+                Exp::List(slots) => slots.iter().collect(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let x = c + 1;
+        if c <= n || x > to || (x == to && self.resolve(x) != self.resolve(next)) { return None; }
+        if self.nodes[c].exit != Exit::Latch(n + 1) || !self.within(n, x, || self.is_region(n + 1, c, c)) { return None; }
+        if self.preds(c).iter().any(|p| *p < n || *p > c) { return None; }
+        let vars = match self.nodes[c].stmts.as_slice() {
+            [Exp::Call(_, _, vars)] => Slots::range(vars),
+            _ => return None,
+        };
+
+        //A single call, such as pairs(t), usually writes all three slots.
+        let explist = match stmts.last() {
+            Some(Exp::Call(_, _, returns)) if Slots::range(returns).iter().map(|s| Exp::Var(*s)).eq(slots.iter().map(|s| (*s).clone())) => {
+                let call = stmts.pop()?;
+                self.inline_call(self.nodes[n].start_index, call, stmts)
+            },
+            _ => Exp::List(self.initializers(self.nodes[n].start_index, &slots, stmts)),
+        };
+
+        let mut body = self.within(n, x, || self.statements(n + 1, c, c));
+        self.continues(c, &mut body);
+        let index = self.nodes[n + 1].start_index;
+        let vars: Vec<Exp> = vars.into_iter().map(|v| self.name(&Exp::Var(v), index, &mut body)).collect();
+        Some(Exp::IterFor(Box::new(Exp::List(vars)), Box::new(explist), body))
+    }
+
+    /// Values last written into slots, taken off the end of stmts. Slots not written there stay as they are.
+    /// Temporaries written in between that feed a single read in those values are inlined into it. Slots
+    /// without a local name at index are temporaries.
+    fn initializers(&self, index: usize, slots: &[&Exp], stmts: &mut Vec<Exp>) -> Vec<Exp> {
+        let mut values: Vec<Option<Exp>> = vec![None; slots.len()];
+        while let Some(stmt @ Exp::Move(dst, v)) = stmts.last() {
+            let (dst, v) = (dst.as_ref().clone(), v.as_ref().clone());
+            match slots.iter().position(|s| **s == dst) {
+                Some(k) if values[k].is_none() => values[k] = Some(v),
+                _ => {
+                    let is_temporary = match dst {
+                        Exp::Var(slot) => self.pt.slot_name(slot, index).is_none() && !Slots::uses(stmt).contains(&slot),
+                        _ => false,
+                    };
+                    if !is_temporary || values.iter().flatten().map(|e| Structurer::reads(e, &dst)).sum::<usize>() != 1 { break; }
+                    for value in values.iter_mut().flatten() {
+                        value.rewrite(&mut |e| {
+                            if *e != dst { return false; }
+                            *e = v.clone();
+                            true
+                        });
+                    }
+                },
+            }
+            stmts.pop();
+        }
+        values.into_iter().zip(slots.iter()).map(|(v, s)| v.unwrap_or_else(|| (*s).clone())).collect()
+    }
+
+    /// A call with the function and params written into its slots right before it taken off the end of stmts.
+    /// Its results are left out, the call is used as an expression.
+    fn inline_call(&self, index: usize, call: Exp, stmts: &mut Vec<Exp>) -> Exp {
+        let (f, params) = match call {
+            Exp::Call(f, params, _) => (f, params),
+            other => return other,
+        };
+        let mut slots: Vec<Exp> = vec![f.as_ref().clone()];
+        slots.extend(Slots::range(&params).into_iter().map(Exp::Var));
+        let mut values = self.initializers(index, &slots.iter().collect::<Vec<&Exp>>(), stmts);
+        let f = values.remove(0);
+        let params = match params.as_ref() {
+            Exp::Range(_, _) | Exp::Var(_) => Exp::List(values),
+            _ => *params, //an open MULTRES end is kept as it is.
+        };
+        Exp::Call(Box::new(f), Box::new(params), Box::new(Exp::Empty))
+    }
+
+    /// A loop variable with its name from the debug info, renamed throughout the loop body.
+    fn name(&self, var: &Exp, index: usize, body: &mut [Exp]) -> Exp {
+        let slot = match var {
+            Exp::Var(slot) => *slot,
+            _ => return var.clone(),
+        };
+        let named = match self.pt.slot_name(slot, index) {
+            Some(name) => Exp::Named(slot, name.to_string()),
+            None => return var.clone(),
+        };
+        for stmt in body.iter_mut() {
+            stmt.rewrite(&mut |e| {
+                if *e != *var { return false; }
+                *e = named.clone();
+                true
+            });
+        }
+        named
+    }
+
+    /// A branch that could not be structured, kept as a conditional goto.
+    fn goto_if(&self, test: Exp, t: usize) -> Exp {
+        let goto = if self.is_break(t) { Exp::Break } else { Exp::GotoLabel(Structurer::label(self.nodes[t].start_index)) };
+        match test {
+            Exp::IsT(copy, d) if *copy != Exp::Empty => Exp::If(d, vec![*copy, goto], vec![]),
+            Exp::IsT(_, d) => Exp::If(d, vec![goto], vec![]),
+            cond => Exp::If(Box::new(cond), vec![goto], vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dis::prototyper::Prototyper;
+    use crate::ir::{
+        blocker::Blocker,
+        locals::Locals,
+        rules::PassManager,
+        translator::{IRBlock, Translator},
+    };
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn var(slot: u16) -> Box<Exp> {
+        Box::new(Exp::Var(slot))
+    }
+
+    fn test(copy: Exp, d: Exp) -> Exp {
+        Exp::IsT(Box::new(copy), Box::new(d))
+    }
+
+    fn structure(file: &str, skip: usize) -> Vec<Exp> {
+        let mut ptr = Prototyper::new(file);
+        let pt = (0..=skip).map(|_| ptr.next().unwrap()).last().unwrap();
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        Structurer::structure(Translator{}.translate_blocks(blocks), &cfg, &pt)
+    }
+
+    fn singleif() -> Prototype {
+        Prototyper::new("singleif.ljc").next().unwrap()
+    }
+
+    /// Nodes from their statements and exits. Node i starts at index i.
+    fn nodes(pt: &Prototype, nodes: Vec<(Vec<Exp>, Exit)>) -> Structurer<'_> {
+        let nodes = nodes.into_iter().enumerate().map(|(i, (stmts, exit))| Node {
+            id: i,
+            start_index: i,
+            stmts: stmts,
+            exit: exit,
+        }).collect();
+        let mut s = Structurer {
+            nodes: nodes,
+            pt: pt,
+            enclosing: Cell::new(None),
+            labels: BTreeSet::new(),
+            liveness: Liveness { live_in: vec![], live_out: vec![] },
+        };
+        //The liveness of the IR the nodes stand for, with the tests ending them.
+        let ir = IRPrototype { proto_ir: s.nodes.iter().map(|n| {
+            let mut block_ir = n.stmts.clone();
+            if let Exit::Branch(e, _) | Exit::ForPrep(e, _) | Exit::IterPrep(e, _) = &n.exit {
+                block_ir.push(e.clone());
+            }
+            IRBlock { id: n.id, start_index: n.start_index, block_ir: block_ir }
+        }).collect() };
+        let succs: Vec<Vec<usize>> = (0..s.nodes.len()).map(|n| s.succs(n)).collect();
+        let preds: Vec<Vec<usize>> = (0..s.nodes.len()).map(|n| (0..s.nodes.len()).filter(|p| succs[*p].contains(&n)).collect()).collect();
+        s.liveness = Liveness::new(&ir, &Cfg { succs: succs, preds: preds });
+        s
+    }
+
+    #[test]
+    fn test_nested_ifs() {
+        let stmts = structure("singleif.ljc", 0);
+        //Each if holds the code after it up to the common return.
+        assert!(stmts.len() == 4, "found: {:?}", stmts);
+        match &stmts[2] {
+            Exp::If(_, then, otherwise) => {
+                assert!(otherwise.is_empty());
+                assert!(then.iter().filter(|e| matches!(e, Exp::If(_, _, _))).count() == 1);
+            },
+            e => panic!("found: {}", e),
+        }
+        assert!(stmts[3] == Exp::Return(Box::new(Exp::Empty)));
+    }
+
+    #[test]
+    fn test_compound_conditions() {
+        let stmts = structure("dec.lua", 0);
+        let ifs: Vec<&Exp> = stmts.iter().filter(|e| matches!(e, Exp::If(_, _, _))).collect();
+        let cond = |i: usize| match ifs[i] {
+            Exp::If(cond, _, _) => cond.as_ref(),
+            _ => unreachable!(),
+        };
+        //if a and b: both tests skip the then part.
+        assert!(matches!(cond(1), Exp::And(_, _)), "found: {}", cond(1));
+        //if a or b: the first test skips the second one.
+        assert!(matches!(cond(2), Exp::Or(_, _)), "found: {}", cond(2));
+        match ifs[3] {
+            Exp::If(_, then, otherwise) => assert!(then.len() == 4 && otherwise.len() == 4),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_merge_values() {
+        //x = a or b, with and without a copying test.
+        let pt = singleif();
+        let mut s = nodes(&pt, vec![
+            (vec![], Exit::Branch(test(Exp::Move(var(2), var(0)), Exp::Var(0)), 2)),
+            (vec![Exp::Move(var(2), var(1))], Exit::Fall),
+            (vec![Exp::Local(var(3), var(0))], Exit::Branch(test(Exp::Empty, Exp::Not(var(3))), 4)),
+            (vec![Exp::Move(var(3), var(1))], Exit::Fall),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        assert!(s.nodes[0].stmts[0].to_string() == "var(2) := (var(0) or var(1))", "found: {}", s.nodes[0].stmts[0]);
+        assert!(s.nodes[2].stmts[0].to_string() == "local var(3) := (var(0) and var(1))", "found: {}", s.nodes[2].stmts[0]);
+        assert!(s.nodes[1].stmts.is_empty() && s.nodes[3].stmts.is_empty());
+    }
+
+    #[test]
+    fn test_merge_ternary() {
+        //x = a and b or c: ISF a jumps to the assignment of c that ISTC b skips.
+        let pt = singleif();
+        let mut s = nodes(&pt, vec![
+            (vec![], Exit::Branch(test(Exp::Empty, Exp::Not(var(0))), 2)),
+            (vec![], Exit::Branch(test(Exp::Move(var(3), var(1)), Exp::Var(1)), 3)),
+            (vec![Exp::Move(var(3), var(2))], Exit::Fall),
+            (vec![Exp::Return(var(3))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let stmts = s.statements(0, 4, 4);
+        assert!(stmts.len() == 2, "found: {:?}", stmts);
+        assert!(stmts[0].to_string() == "var(3) := ((var(0) and var(1)) or var(2))", "found: {}", stmts[0]);
+    }
+
+    #[test]
+    fn test_merge_value_of_call() {
+        //x = f(y) or 0: the call returns into the tested slot. Without a value written there, nothing is merged.
+        let pt = singleif();
+        let call = |a: u16| Exp::Call(var(a), Box::new(Exp::Range(a as u32 + 1, a as u32 + 1)), Box::new(Exp::Range(a as u32, a as u32)));
+        let mut s = nodes(&pt, vec![
+            (vec![Exp::Move(var(3), var(0)), call(2)], Exit::Branch(test(Exp::Empty, Exp::Var(2)), 2)),
+            (vec![Exp::Move(var(2), Box::new(Exp::Lit(0)))], Exit::Fall),
+            (vec![call(5)], Exit::Branch(test(Exp::Empty, Exp::Var(4)), 4)),
+            (vec![Exp::Move(var(4), Box::new(Exp::Lit(0)))], Exit::Fall),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let merged = s.nodes[0].stmts.last().unwrap().to_string();
+        assert!(merged == "var(2) := (call(var(2), params(3->3), returns((empty))) or lit(0))", "found: {}", merged);
+        assert!(matches!(s.nodes[2].exit, Exit::Branch(_, 4)) && s.nodes[3].stmts.len() == 1);
+
+        //local offset_length = Vector3.length(hit_offset) or 0
+        let protos = structure_all("beam_system_client.lua", &PassManager::default());
+        let main: String = protos.values().last().unwrap().body.iter().map(|e| e.to_string()).collect();
+        assert!(main.contains("local offset_length := (call(") && !main.contains("(offset_length or 0)"));
+    }
+
+    #[test]
+    fn test_merge_ternary_computed() {
+        //x = a and f(a) or nil: the value tested after a is computed into x by a call.
+        let pt = singleif();
+        let f = Exp::Table(Box::new(Exp::Global), Box::new(Exp::Literal(LuaValue::Str("f".to_string()))));
+        let mut s = nodes(&pt, vec![
+            (vec![], Exit::Branch(test(Exp::Empty, Exp::Not(var(0))), 2)),
+            (vec![
+                Exp::Move(var(3), Box::new(f)),
+                Exp::Move(var(4), var(0)),
+                Exp::Call(var(3), Box::new(Exp::Range(4, 4)), Box::new(Exp::Range(3, 3))),
+            ], Exit::Branch(test(Exp::Empty, Exp::Var(3)), 3)),
+            (vec![Exp::Move(var(3), Box::new(Exp::Pri(0)))], Exit::Fall),
+            (vec![Exp::Return(var(3))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let stmts = s.statements(0, 4, 4);
+        assert!(stmts.len() == 2, "found: {:?}", stmts);
+        assert!(stmts[0].to_string() == "var(3) := ((var(0) and call(f, params(var(0)), returns((empty)))) or pri(0))", "found: {}", stmts[0]);
+    }
+
+    #[test]
+    fn test_merge_chained_conditions() {
+        //if a.x > 0 and b.x > 0 or a.y > 0 and b.y > 0 then return true end, each test on the temporary
+        //its node loads. Every test after the first takes its temporary into the condition.
+        let pt = singleif();
+        let load = |obj: u16, key: &str| Exp::Move(var(2), Box::new(Exp::Table(var(obj), Box::new(Exp::Literal(LuaValue::Str(key.to_string()))))));
+        let positive = || Exp::Comparison(var(2), Box::new(Exp::Gt), Box::new(Exp::Lit(0)));
+        let not_positive = || Exp::Not(Box::new(positive()));
+        let mut s = nodes(&pt, vec![
+            (vec![load(0, "x")], Exit::Branch(not_positive(), 2)),
+            (vec![load(1, "x")], Exit::Branch(positive(), 4)),
+            (vec![load(0, "y")], Exit::Branch(not_positive(), 5)),
+            (vec![load(1, "y")], Exit::Branch(not_positive(), 5)),
+            (vec![Exp::Move(var(2), Box::new(Exp::Pri(2))), Exp::Return(var(2))], Exit::Stop),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let stmts = s.statements(0, 6, 6);
+        assert!(stmts.len() == 3, "found: {:?}", stmts);
+        match &stmts[1] {
+            Exp::If(cond, then, otherwise) => {
+                assert!(cond.to_string() == "(((var(2) > lit(0)) and (var(1).x > lit(0))) or ((var(0).y > lit(0)) and (var(1).y > lit(0))))", "found: {}", cond);
+                assert!(then.len() == 2 && matches!(then[1], Exp::Return(_)) && otherwise.is_empty(), "found: {:?}", then);
+            },
+            e => panic!("found: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_read_temporaries() {
+        //The second test loads var(2), which is read after it. Without its load, var(2) would be unset.
+        let pt = singleif();
+        let lt = |a: u16, b: u16| Exp::Comparison(var(a), Box::new(Exp::Lt), var(b));
+        let mut s = nodes(&pt, vec![
+            (vec![], Exit::Branch(lt(0, 1), 2)),
+            (vec![Exp::Move(var(2), var(0))], Exit::Branch(lt(2, 1), 3)),
+            (vec![Exp::Move(var(3), var(2))], Exit::Fall),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        assert!(s.nodes[0].exit == Exit::Branch(lt(0, 1), 2), "found: {:?}", s.nodes[0].exit);
+        assert!(s.nodes[1].stmts.len() == 1);
+    }
+
+    #[test]
+    fn test_unstructured_branch() {
+        //A backward branch is kept as a conditional goto.
+        let pt = singleif();
+        let mut s = nodes(&pt, vec![
+            (vec![Exp::Move(var(0), var(1))], Exit::Fall),
+            (vec![], Exit::Branch(Exp::Comparison(var(0), Box::new(Exp::Lt), var(1)), 0)),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let stmts = s.statements(0, 3, 3);
+        assert!(stmts[1] == Exp::If(Box::new(Exp::Comparison(var(0), Box::new(Exp::Lt), var(1))), vec![Exp::GotoLabel("label_0".to_string())], vec![]));
+    }
+
+    #[test]
+    fn test_numeric_for() {
+        let stmts = structure("dec.lua", 1);
+        let loops: Vec<String> = stmts.iter().filter(|e| matches!(e, Exp::For(_, _, _, _, _))).map(|e| e.to_string()).collect();
+        assert!(loops.len() == 2, "found: {:?}", loops);
+        //for y = 0, 10 do print(y) end: the step of 1 is left out and the FORL is consumed.
+        assert!(loops[0].starts_with("for y = lit(0), lit(10) do\n\tvar(6) := _G[str(0)]\n\tvar(7) := y\n"), "found: {}", loops[0]);
+        assert!(loops[0].ends_with("returns((empty)))\nend"), "found: {}", loops[0]);
+        //The inner loop is nested in the body of the outer one.
+        assert!(loops[1].contains("\tfor z = lit(0), lit(10) do\n"), "found: {}", loops[1]);
+        assert!(!loops[1].contains("FORL"));
+    }
+
+    #[test]
+    fn test_numeric_for_temporaries() {
+        //for i = 1, #t do: the table loaded into the stop slot for its length goes into the stop.
+        let pt = singleif();
+        let marker = Exp::For(var(13), var(10), var(11), var(12), vec![]);
+        let mut s = nodes(&pt, vec![
+            (vec![
+                Exp::Move(var(10), Box::new(Exp::Lit(1))),
+                Exp::Move(var(11), var(20)),
+                Exp::Move(var(11), Box::new(Exp::Len(var(11)))),
+                Exp::Move(var(12), Box::new(Exp::Lit(1))),
+            ], Exit::ForPrep(marker, 2)),
+            (vec![Exp::Move(var(14), var(13))], Exit::Latch(1)),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        s.merge_conditions();
+        let stmts = s.statements(0, 3, 3);
+        assert!(stmts[0].to_string() == "for var(13) = lit(1), len(var(20)) do\n\tvar(14) := var(13)\nend", "found: {:?}", stmts);
+    }
+
+    #[test]
+    fn test_generic_for() {
+        let stmts = structure("dec.lua", 1);
+        let loops: Vec<String> = stmts.iter().filter(|e| matches!(e, Exp::IterFor(_, _, _))).map(|e| e.to_string()).collect();
+        assert!(loops.len() == 3, "found: {:?}", loops);
+        //for k, v in pairs(t): the iterator call becomes the explist and its results the named variables.
+        assert!(loops[0].starts_with("for k, v in call(_G[str(4)], params(var(2)), returns((empty))) do\n"), "found: {}", loops[0]);
+        assert!(loops[0].contains("\tvar(10) := k\n\tvar(11) := v\n"), "found: {}", loops[0]);
+        //for k, v in next, t, nil: the three values written to the generator, state and control slots.
+        assert!(loops[2].starts_with("for k, v in _G[str(6)], var(2), pri(0) do\n"), "found: {}", loops[2]);
+        assert!(loops.iter().all(|l| !l.contains("ITERL") && !l.ends_with("returns(8->9))\nend")));
+    }
+
+    #[test]
+    fn test_while_loops() {
+        let stmts = structure("dec.lua", 1);
+        let loops: Vec<String> = stmts.iter().filter(|e| matches!(e, Exp::While(_, _))).map(|e| e.to_string()).collect();
+        assert!(loops.len() == 3, "found: {:?}", loops);
+        //while x < 10 do: the constant loaded for the test goes into the condition.
+        assert!(loops[0].starts_with("while (var(0) < lit(10)) do\n\tvar(0) := (var(0) + num(0))\n"), "found: {}", loops[0]);
+        assert!(loops[1].contains("\n\twhile (var(1) < lit(10)) do\n\t\tvar(1) := (var(1) + num(0))\n\tend\n"), "found: {}", loops[1]);
+        assert!(loops[2].contains("\t\tif (var(2) == num(0)) then\n"), "found: {}", loops[2]);
+        assert!(loops.iter().all(|l| !l.contains("goto") && !l.contains("LOOP")));
+    }
+
+    #[test]
+    fn test_repeat_loop() {
+        let stmts = structure("dec.lua", 1);
+        let loops: Vec<String> = stmts.iter().filter(|e| matches!(e, Exp::Repeat(_, _))).map(|e| e.to_string()).collect();
+        assert!(loops.len() == 1, "found: {:?}", loops);
+        assert!(loops[0].starts_with("repeat\n\tvar(0) := (var(0) + num(1))\n"), "found: {}", loops[0]);
+        assert!(loops[0].ends_with("returns((empty)))\nuntil (lit(10) <= var(0))"), "found: {}", loops[0]);
+    }
+
+    #[test]
+    fn test_loop_break() {
+        //while a < b do if a == b then break end a = b end
+        let pt = singleif();
+        let lt = Exp::Comparison(var(0), Box::new(Exp::Lt), var(1));
+        let eq = Exp::Comparison(var(0), Box::new(Exp::Equals), var(1));
+        let s = nodes(&pt, vec![
+            (vec![], Exit::Branch(lt.clone().negate(), 3)),
+            (vec![Exp::Redundant("LOOP".to_string())], Exit::Branch(eq.clone(), 3)),
+            (vec![Exp::Move(var(0), var(1))], Exit::Jump(0)),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        let stmts = s.statements(0, 4, 4);
+        assert!(stmts[0] == Exp::While(Box::new(lt), vec![
+            Exp::If(Box::new(eq), vec![Exp::Break], vec![]),
+            Exp::Move(var(0), var(1)),
+        ]), "found: {}", stmts[0]);
+        assert!(stmts.len() == 2);
+    }
+
+    #[test]
+    fn test_repeat_closing_upvalues() {
+        //The test jumps to a UCLO looping back, the jump past it leaves the loop.
+        let pt = singleif();
+        let call = Exp::Call(var(2), Box::new(Exp::Range(3, 3)), Box::new(Exp::Empty));
+        let lt = Exp::Comparison(var(0), Box::new(Exp::Lt), var(1));
+        let s = nodes(&pt, vec![
+            (vec![Exp::Redundant("LOOP".to_string()), call.clone()], Exit::Branch(lt.clone(), 2)),
+            (vec![], Exit::Jump(3)),
+            (vec![], Exit::Jump(0)),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        let stmts = s.statements(0, 4, 4);
+        assert!(stmts[0] == Exp::Repeat(vec![call], Box::new(lt.negate())), "found: {}", stmts[0]);
+        assert!(stmts.len() == 2);
+    }
+
+    #[test]
+    fn test_goto_labels() {
+        let stmts = structure("dec.lua", 2);
+        let goto = Exp::GotoLabel("label_18".to_string());
+        let at = |e: &Exp| stmts.iter().position(|s| s == e);
+        //The goto past the if goes to a label at the same level, not into its else part.
+        assert!(at(&goto).is_some() && at(&goto) < at(&Exp::Label("label_18".to_string())), "found: {:?}", stmts);
+        assert!(stmts.iter().all(|s| !matches!(s, Exp::If(_, _, otherwise) if !otherwise.is_empty())));
+        //The return before the next label only fits at the end of a block.
+        let label = at(&Exp::Label("label_20".to_string())).unwrap();
+        assert!(matches!(&stmts[label - 1], Exp::Do(body) if matches!(body[..], [Exp::Return(_)])), "found: {}", stmts[label - 1]);
+        assert!(matches!(stmts.last(), Some(Exp::Return(_))));
+    }
+
+    #[test]
+    fn test_label_scopes() {
+        //if c then goto label_5 end local x = 1 ... ::label_5:: cannot jump into the scope of x.
+        let x = || Box::new(Exp::Named(1, "x".to_string()));
+        let goto = Exp::If(var(0), vec![Exp::GotoLabel("label_5".to_string())], vec![]);
+        let label = Exp::Label("label_5".to_string());
+        let before = |last: Exp| vec![
+            goto.clone(),
+            Exp::Local(x(), Box::new(Exp::Lit(1))),
+            Exp::Move(var(2), x()),
+            label.clone(),
+            last,
+        ];
+
+        //Not read past the label, x goes in a do block.
+        let mut stmts = before(Exp::Return(var(2)));
+        Structurer::label_scopes(&mut stmts);
+        assert!(stmts == [
+            goto.clone(),
+            Exp::Do(vec![Exp::Local(x(), Box::new(Exp::Lit(1))), Exp::Move(var(2), x())]),
+            label.clone(),
+            Exp::Return(var(2)),
+        ], "found: {:?}", stmts);
+
+        //Read past the label, x is declared before the goto.
+        let mut stmts = before(Exp::Return(x()));
+        Structurer::label_scopes(&mut stmts);
+        assert!(stmts == [
+            Exp::Local(x(), Box::new(Exp::Pri(0))),
+            goto.clone(),
+            Exp::Move(x(), Box::new(Exp::Lit(1))),
+            Exp::Move(var(2), x()),
+            label.clone(),
+            Exp::Return(x()),
+        ], "found: {:?}", stmts);
+
+        //A label ending the block is out of the scope of x.
+        let mut stmts = before(Exp::Return(x()));
+        stmts.pop();
+        let unchanged = stmts.clone();
+        Structurer::label_scopes(&mut stmts);
+        assert!(stmts == unchanged);
+    }
+
+    #[test]
+    fn test_goto_continue() {
+        //while a < b do if a == b then goto continue end a = b ::continue:: end
+        let pt = singleif();
+        let lt = Exp::Comparison(var(0), Box::new(Exp::Lt), var(1));
+        let eq = Exp::Comparison(var(0), Box::new(Exp::Equals), var(1));
+        let s = nodes(&pt, vec![
+            (vec![], Exit::Branch(lt.clone().negate(), 3)),
+            (vec![Exp::Redundant("LOOP".to_string())], Exit::Branch(eq.clone(), 0)),
+            (vec![Exp::Move(var(0), var(1))], Exit::Jump(0)),
+            (vec![Exp::Return(Box::new(Exp::Empty))], Exit::Stop),
+        ]);
+        let stmts = s.statements(0, 4, 4);
+        assert!(stmts[0] == Exp::While(Box::new(lt), vec![
+            Exp::If(Box::new(eq), vec![Exp::GotoLabel("continue".to_string())], vec![]),
+            Exp::Move(var(0), var(1)),
+            Exp::Label("continue".to_string()),
+        ]), "found: {}", stmts[0]);
+    }
+
+    #[test]
+    fn test_uclo_scopes() {
+        //A UCLO leaving a scope jumps on and leaves a marker where the scope ends.
+        let starts: HashMap<usize, usize> = vec![(0, 0), (5, 1)].into_iter().collect();
+        let mut stmts = vec![Exp::UClo(1, Box::new(Exp::Jump(5)))];
+        assert!(Structurer::exit(&mut stmts, &starts, &[1], &[1]) == Exit::Jump(1));
+        assert!(stmts == [Exp::UClo(1, Box::new(Exp::Empty))]);
+
+        //x = 0 do local y = 1 x = y end x = 2 if x then local z = x break end
+        let local = |slot: u16, v: Exp| Exp::Local(var(slot), Box::new(v));
+        let mut stmts = vec![
+            Exp::Move(var(0), Box::new(Exp::Lit(0))),
+            local(1, Exp::Lit(1)),
+            Exp::Move(var(0), var(1)),
+            Exp::UClo(1, Box::new(Exp::Empty)),
+            Exp::Move(var(0), Box::new(Exp::Lit(2))),
+            Exp::If(var(0), vec![local(2, Exp::Var(0)), Exp::UClo(2, Box::new(Exp::Empty)), Exp::Break], vec![]),
+        ];
+        Structurer::scopes(&mut stmts);
+        assert!(stmts[1] == Exp::Do(vec![local(1, Exp::Lit(1)), Exp::Move(var(0), var(1))]), "found: {}", stmts[1]);
+        assert!(stmts[2] == Exp::Move(var(0), Box::new(Exp::Lit(2))));
+        //The end of the then part closes the scope already.
+        assert!(stmts[3] == Exp::If(var(0), vec![local(2, Exp::Var(0)), Exp::Break], vec![]), "found: {}", stmts[3]);
+    }
+
+    #[test]
+    fn test_inline_closures() {
+        let protos = structure_all("dec.lua", &PassManager::new(vec![]));
+        let main: Vec<String> = protos.values().last().unwrap().body.iter().map(|e| e.to_string()).collect();
+        let functions: Vec<&String> = main.iter().filter(|s| s.starts_with("local function ")).collect();
+        assert!(functions.len() == 5, "found: {:?}", main);
+        //Each function is declared under its local's name, the named parameter replaces its slot in the body
+        //and the vararg function takes ...
+        assert!(functions[2].starts_with("local function derp(x)\n"), "found: {}", functions[2]);
+        assert!(functions[2].contains("x[str(0)]") && !functions[2].contains("var(0)"), "found: {}", functions[2]);
+        assert!(functions[4].starts_with("local function vargs_multirets(...)\n"), "found: {}", functions[4]);
+        assert!(functions[0].ends_with("\treturn\nend"), "found: {}", functions[0]);
+
+        //Methods of the module table are printed under their recovered name.
+        let protos = structure_all("beam_system_client.lua", &PassManager::default());
+        let main: Vec<String> = protos.values().last().unwrap().body.iter().map(|e| e.to_string()).collect();
+        let method = main.iter().find(|s| s.starts_with("function ClientBeamSystem:finalize_setup(context)\n"));
+        assert!(method.is_some(), "found: {:?}", main);
+        assert!(!main.iter().any(|s| s.starts_with("function var(")), "found: {:?}", main);
+    }
+
+    //Runs the pipeline over every prototype of file, children before their parents.
+    fn structure_all(file: &str, pm: &PassManager) -> BTreeMap<usize, ProtoSummary> {
+        let mut ptr = Prototyper::new(file);
+        let mut protos: BTreeMap<usize, ProtoSummary> = BTreeMap::new();
+        while let Some(pt) = ptr.next() {
+            let blocks = Blocker{}.make_blocks(&pt);
+            let cfg = Cfg::new(&blocks);
+            let mut ir = Translator{}.translate_blocks(blocks);
+            Locals::declare(&mut ir, &cfg, &pt);
+            let ctx = RuleContext::with_protos(&pt, &cfg, &protos);
+            pm.run(&mut ir, &ctx);
+            let mut stmts = Structurer::structure(ir, &cfg, &pt);
+            Structurer::inline_closures(&mut stmts, &ctx);
+            let mut summary = ProtoSummary::new(&pt);
+            summary.body = stmts;
+            protos.insert(pt.header.id, summary);
+        }
+        protos
+    }
+}
@@ -8,9 +8,11 @@ use crate::{
 pub struct Arith{}
 impl Arith {
     pub fn arith(bci: &Bci) -> Exp {
+        if bci.op == 36 { return Arith::cat(bci) }
+
         let (a, b) = (Box::new(Exp::Var(bci.a() as u16)), Box::new(Exp::Var(bci.b() as u16)));
         let c;
-        if (30..=34).contains(&bci.op) { //vv op
+        if (30..=35).contains(&bci.op) { //vv op and POW
             c = Box::new(Exp::Var(bci.c() as u16));
         } else { //vn or nv
             c = Box::new(Exp::Num(bci.c() as u16));
@@ -25,14 +27,22 @@ impl Arith {
 
     fn binop(bci: &Bci, b: Box<Exp>, c: Box<Exp>) -> Exp {
         match bci.op % 5 {
+            0 if bci.op == 35   => Exp::Pow(b, c),
             0                   => Exp::Add(b, c),
-            1 if bci.op == 31   => Exp::Pow(b, c),
             1                   => Exp::Sub(b, c),
-            2 if bci.op == 32   => Exp::Cat(b, c),
             2                   => Exp::Mul(b, c),
             3                   => Exp::Div(b, c),
             4                   => Exp::Mod(b, c),
             _                   => Exp::Error("binop".to_string()),
         }
     }
+
+    //CAT: A = B .. B+1 .. ... .. C. Concatenation is right associative, so the chain nests to the right.
+    fn cat(bci: &Bci) -> Exp {
+        let (b, c) = (bci.b() as u16, bci.c() as u16);
+        let mut operands = (b..=c).rev().map(Exp::Var);
+        let last = operands.next().unwrap_or(Exp::Error("cat: empty range".to_string()));
+        let chain = operands.fold(last, |rhs, lhs| Exp::Cat(Box::new(lhs), Box::new(rhs)));
+        Exp::Move(Box::new(Exp::Var(bci.a() as u16)), Box::new(chain))
+    }
 }
\ No newline at end of file
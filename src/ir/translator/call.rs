@@ -17,39 +17,52 @@ impl Call {
         let d = bci.d();
         match bci.op {
             61 => Call::callm(bci),
-            //CALL: A(A+!...A+C-1) but A+C for exclusive range.
+            //CALL: A, ..., A+B-2 = A(A+1, ..., A+C-1).
             62 => Exp::Call(Box::new(Exp::Var(a)), 
-                Box::new(Exp::Range((a+1) as u32, (a+c-1) as u32)), 
-                Box::new(Exp::Range((a+1) as u32, (a+b-1) as u32))),
+                Box::new(Call::slots(a + 1, c - 1)), 
+                Box::new(Call::results(a, b))),
             63 => Exp::Return(Box::new(Call::callm(bci))),
-            //CALLT: return A(A+1...A+D-1) but A+D for exclusive range.
+            //CALLT: return A(A+1, ..., A+D-1). A tail call returns whatever the callee returns.
             64 => Exp::Return(Box::new(Exp::Call(Box::new(Exp::Var(a)), 
-                Box::new(Exp::Range((a+1) as u32, (a+d-1) as u32)), 
-                Box::new(Exp::Range((a+1) as u32, (a+b-1) as u32))))),
-            //ITERC/N is handled a lot similarly to FORI/L
-            65 => Exp::Redundant("ITERC".to_string()),
-            66 => Exp::Redundant("ITERN".to_string()),
-            67 => Exp::VarArg(Box::new(Exp::Range((a+b-2) as u32, a as u32))), //a+b-2 -> a-1 inclusive, (a is varg slot)?.
+                Box::new(Call::slots(a + 1, d - 1)), 
+                Box::new(Exp::MultRes(a))))),
+            //ITERC/N: A, ..., A+B-2 = A-3(A-2, A-1). The generator, state and control are copied up first.
+            65 | 66 => Exp::Call(Box::new(Exp::Var(a - 3)),
+                Box::new(Exp::Range((a - 2) as u32, (a - 1) as u32)),
+                Box::new(Call::results(a, b))),
+            67 => Exp::VarArg(Box::new(Call::results(a, b))), //VARG: A, ..., A+B-2 = ...
             _  => Exp::Error("call".to_string()),
         }
     }
 
+    //CALLM: A, ..., A+B-2 = A(A+1, ..., A+C, MULTRES). CALLMT: return A(A+1, ..., A+D, MULTRES).
+    // The multiple results of the previous call or vararg start right after the fixed params.
     fn callm(bci: &Bci) -> Exp {
-        //CALLM has an additional param of '...' unless another CALLM is a
-        // parameter to the current CALLM. In which case, give the varg to 
-        // the nested CALLMs.
-
-        //fn name in slot A. Slot A is reused as a return slot for fn if returning.
-        //fixed returns go in slots: A to A+B (+1 exclusive?)
-        //fixed params are slots: A+1 to A+C (+1 exclusive?)
         let a = bci.a() as u16;
-        let c = bci.c() as u16;
-        let b = bci.b() as u16;
+        let (fixed, returns) = if bci.op == 63 {
+            (bci.d(), Exp::MultRes(a)) //a tail call returns whatever the callee returns.
+        } else {
+            (bci.c() as u16, Call::results(a, bci.b() as u16))
+        };
 
         let f_name = Box::new(Exp::Var(a));
-        let param_range = Box::new(Exp::Range((a+1) as u32, (a+c+1) as u32));
-        let return_range = Box::new(Exp::Range(a as u32, (a+b) as u32));
+        let mut params = vec![Exp::MultRes(a + fixed + 1)];
+        if fixed > 0 {
+            params.insert(0, Call::slots(a + 1, fixed));
+        }
+        Exp::Call(f_name, Box::new(Exp::List(params)), Box::new(returns))
+    }
+
+    //B-1 results starting at A. B = 0 leaves every result in MULTRES.
+    pub fn results(a: u16, b: u16) -> Exp {
+        if b == 0 { Exp::MultRes(a) } else { Call::slots(a, b - 1) }
+    }
 
-        Exp::Call(f_name, param_range, return_range)
+    //count slots starting at first.
+    pub fn slots(first: u16, count: u16) -> Exp {
+        match count {
+            0 => Exp::Empty,
+            _ => Exp::Range(first as u32, (first + count - 1) as u32),
+        }
     }
-}
\ No newline at end of file
+}
@@ -31,28 +31,21 @@ impl Comparison {
                 op if op < 12   => Exp::Pri(bci.d()),
                 _               => Exp::Error("comparison.d".to_string()),
             };
-            let op = Comparison::comparison_op(bci);
-            let a = Box::new(a);
-            let d = Box::new(d);
-            let op = Box::new(op);
-
-            Exp::Comparison(a, op, d)
+            Comparison::jump_condition(bci.op, a, d)
         }
     }
 
-    fn comparison_op(bci: &Bci) -> Exp {
-        match bci.op {
-            0 if (bci.a() as u16) <= bci.d()            => Exp::Not(Box::new(Exp::Lt)),
-            0 if (bci.a() as u16) > bci.d()             => Exp::Not(Box::new(Exp::Gt)),
-            1 if (bci.a() as u16) <= bci.d()            => Exp::Lt,
-            1 if (bci.a() as u16) > bci.d()             => Exp::Gt, 
-            2 if (bci.a() as u16) <= bci.d()            => Exp::Not(Box::new(Exp::Lte)),
-            2 if (bci.a() as u16) > bci.d()             => Exp::Not(Box::new(Exp::Gte)),
-            3 if (bci.a() as u16) <= bci.d()            => Exp::Lte,
-            3 if (bci.a() as u16) > bci.d()             => Exp::Gte,
-            op if (4..=11).contains(&op) && op % 2 == 0 => Exp::Equals,
-            op if (4..=11).contains(&op) && op % 2 == 1 => Exp::Not(Box::new(Exp::Equals)),
-            _                                           => Exp::Error("comparison_op".to_string()),
-        }
+    /// Condition under which the JMP following a comparison is taken. ISGE and ISGT jump whenever ISLT and
+    /// ISLE would not, NaN included, so they are negations rather than >= and >.
+    fn jump_condition(op: u8, a: Exp, d: Exp) -> Exp {
+        let (cmp, negated) = match op {
+            0 | 1                   => (Exp::Lt, op == 1),
+            2 | 3                   => (Exp::Lte, op == 3),
+            op if op % 2 == 0       => (Exp::Equals, false),
+            _                       => (Exp::NotEquals, false),
+        };
+        //A and D keep the order of the instruction, the parser has already turned x > y into y < x.
+        let comparison = Exp::Comparison(Box::new(a), Box::new(cmp), Box::new(d));
+        if negated { Exp::Not(Box::new(comparison)) } else { comparison }
     }
-}
\ No newline at end of file
+}
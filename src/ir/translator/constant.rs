@@ -10,15 +10,22 @@ impl Constant {
     pub fn constant(bci: &Bci) -> Exp {
         let value = match bci.op {
             37 => Exp::Str(bci.d()),
-            38 => Exp::Error("KCDATA is unimplemented.".to_string()),
-            39 => Exp::Lit(bci.d()),
-            40 => Exp::Var(bci.d()),
+            38 => Exp::CData(bci.d()),
+            39 => Exp::Lit(bci.sd() as i32), //KSHORT
+            40 => Exp::Num(bci.d()), //KNUM
             41 => Exp::Pri(bci.d()),
-            42 => Exp::Error("KNIL is unimplemented.".to_string()),
+            42 => return Constant::knil(bci),
             _  => Exp::Error("constant.value".to_string()),
         };
         let dst = Box::new(Exp::Var(bci.a() as u16));
         let value = Box::new(value);
         Exp::Move(dst, value)
     }
+
+    //KNIL: slots A..D = nil.
+    fn knil(bci: &Bci) -> Exp {
+        let (a, d) = (bci.a() as u32, bci.d() as u32);
+        let dst = if a == d { Exp::Var(a as u16) } else { Exp::Range(a, d) };
+        Exp::Move(Box::new(dst), Box::new(Exp::Pri(0)))
+    }
 }
\ No newline at end of file
@@ -13,4 +13,15 @@ impl Func {
         let func = Exp::Func(proto_index, Box::new(Exp::Empty));
         Exp::Move(Box::new(Exp::Var(bci.a() as u16)), Box::new(func))
     }
-}
\ No newline at end of file
+
+    //FUNCF, FUNCV and their interpreter and JIT variants head a Lua function, FUNCC and FUNCCW a C function.
+    //Dumps leave the header out, the signature comes from the prototype's flags and parameter count.
+    pub fn header(bci: &Bci) -> Exp {
+        match bci.op {
+            85..=87 => Exp::Redundant("FUNCF".to_string()),
+            88..=90 => Exp::Redundant("FUNCV".to_string()),
+            91 | 92 => Exp::Redundant("FUNCC".to_string()),
+            _       => Exp::Error("func header".to_string()),
+        }
+    }
+}
@@ -30,28 +30,30 @@ use crate::{
 };
 
 pub struct IRBlock {
-    next: usize,
-    block_ir: Vec<Exp>,
+    pub id: usize,
+    pub start_index: usize,
+    pub block_ir: Vec<Exp>,
 }
-impl Iterator for IRBlock {
-    type Item = Exp;
-    fn next(&mut self) -> Option<Self::Item> {
-        let expression = self.block_ir[self.next];
-        self.next += 1;
-        Some(expression)
+impl IRBlock {
+    pub fn iter(&self) -> std::slice::Iter<'_, Exp> {
+        self.block_ir.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Exp> {
+        self.block_ir.iter_mut()
     }
 }
 
 pub struct IRPrototype {
-    next: usize,
-    proto_ir: Vec<IRBlock>,
+    pub proto_ir: Vec<IRBlock>,
 }
-impl <'a> Iterator for IRPrototype {
-    type Item = &'a IRBlock;
-    fn next(&mut self) -> Option<Self::Item> {
-        let block = self.proto_ir[self.next];
-        self.next += 1;
-        Some(block)
+impl IRPrototype {
+    pub fn iter(&self) -> std::slice::Iter<'_, IRBlock> {
+        self.proto_ir.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, IRBlock> {
+        self.proto_ir.iter_mut()
     }
 }
 
@@ -64,7 +66,6 @@ impl Translator {
             prototype_ir.push(self.translate_block(&block));
         }
         IRPrototype {
-            next: 0,
             proto_ir: prototype_ir,
         }
     }
@@ -75,7 +76,8 @@ impl Translator {
             block_ir.push(self.translate_bci(&bci));
         }
         IRBlock {
-            next: 0,
+            id: block.id,
+            start_index: block.start_index,
             block_ir: block_ir,
         }
     }
@@ -96,7 +98,7 @@ impl Translator {
             78..=80 => Loop::iter_loop(bci),
             81..=83 => Loop::while_loop(bci),
             84      => Exp::Jump(bci.get_jump_target()),
-            85..=92 => Exp::Error("85->92, func.rs".to_string()),
+            85..=92 => Func::header(bci),
             93      => Exp::Goto(bci.get_jump_target()),
             94      => Loop::iter_jump(bci),
 
@@ -139,6 +141,72 @@ mod tests {
         blr.make_blocks(&pt)
     }
 
+    #[test]
+    fn test_translate_signed_operands() {
+        let t = Translator{};
+        //KSHORT 0, -1 and TGETB 0, 1, 255.
+        let kshort = Bci::new(0, 39, 0, 0xff, 0xff);
+        assert!(t.translate_bci(&kshort).to_string() == "var(0) := lit(-1)", "found: {}", t.translate_bci(&kshort));
+        let tgetb = Bci::new(0, 56, 0, 0xff, 1);
+        assert!(t.translate_bci(&tgetb).to_string() == "var(0) := var(1)[lit(255)]", "found: {}", t.translate_bci(&tgetb));
+        //KNUM 0, 3 loads number constant 3, not slot 3.
+        let knum = Bci::new(0, 40, 0, 3, 0);
+        assert!(t.translate_bci(&knum).to_string() == "var(0) := num(3)", "found: {}", t.translate_bci(&knum));
+        //JMP back by 3: D = 0x8000 - 3.
+        let jmp = Bci::new(10, 84, 0, 0xfd, 0x7f);
+        assert!(jmp.jump_offset() == -3);
+        assert!(jmp.get_jump_target() == 8);
+    }
+
+    #[test]
+    fn test_translate_cat_pow() {
+        let t = Translator{};
+        //CAT 0, 1..3 concatenates every slot of the range, POW 0, 1, 2 reads two slots.
+        let cat = Bci::new(0, 36, 0, 3, 1);
+        assert!(t.translate_bci(&cat).to_string() == "var(0) := (var(1) .. var(2) .. var(3))", "found: {}", t.translate_bci(&cat));
+        let pow = Bci::new(0, 35, 0, 2, 1);
+        assert!(t.translate_bci(&pow).to_string() == "var(0) := (var(1)^var(2))", "found: {}", t.translate_bci(&pow));
+    }
+
+    #[test]
+    fn test_translate_comparisons() {
+        let t = Translator{};
+        let tr = |op, a, d| t.translate_bci(&Bci::new(0, op, a, (d & 0xff) as u8, (d >> 8) as u8));
+        //if x < y: ISGE x y skips the then part when not x < y.
+        assert!(tr(1, 0, 1).to_string() == "not (var(0) < var(1))", "found: {}", tr(1, 0, 1));
+        assert!(tr(1, 0, 1).negate().to_string() == "(var(0) < var(1))");
+        //if x > y: ISGE y x, the operands stay in the order of the instruction.
+        assert!(tr(1, 1, 0).negate().to_string() == "(var(1) < var(0))", "found: {}", tr(1, 1, 0).negate());
+        //if x >= y: ISGT y x.
+        assert!(tr(3, 1, 0).negate().to_string() == "(var(1) <= var(0))", "found: {}", tr(3, 1, 0).negate());
+        //if not (x <= y): ISLE x y. The negation is kept since x > y is false for NaN.
+        assert!(tr(2, 0, 1).negate().to_string() == "not (var(0) <= var(1))", "found: {}", tr(2, 0, 1).negate());
+        //if x == "s": ISNES x "s". Equality negates exactly.
+        assert!(tr(7, 0, 2).to_string() == "(var(0) ~= str(2))", "found: {}", tr(7, 0, 2));
+        assert!(tr(7, 0, 2).negate().to_string() == "(var(0) == str(2))");
+    }
+
+    #[test]
+    fn test_translate_multres() {
+        let t = Translator{};
+        let tr = |op, a, c, b| t.translate_bci(&Bci::new(0, op, a, c, b)).to_string();
+        //VARG 1, B = 0: every vararg from slot 1 on. VARG 1, B = 3: local a, b = ...
+        assert!(tr(67, 1, 1, 0) == "varg(multres(1))", "found: {}", tr(67, 1, 1, 0));
+        assert!(tr(67, 1, 1, 3) == "varg(1->2)", "found: {}", tr(67, 1, 1, 3));
+        //CALL 0, B = 0, C = 2: f(x) leaving every result in MULTRES.
+        assert!(tr(62, 0, 2, 0) == "call(var(0), params(1->1), returns(multres(0)))", "found: {}", tr(62, 0, 2, 0));
+        //CALLM 0, B = 2, C = 1: one fixed param, then the previous MULTRES.
+        assert!(tr(61, 0, 1, 2) == "call(var(0), params(1->1, multres(2)), returns(0->0))", "found: {}", tr(61, 0, 1, 2));
+        //CALLMT 0, D = 0: return f(...).
+        assert!(tr(63, 0, 0, 0) == "return call(var(0), params(multres(1)), returns(multres(0)))", "found: {}", tr(63, 0, 0, 0));
+        //RETM 2, D = 1: return a, ...
+        assert!(tr(69, 2, 1, 0) == "return var(2), multres(3)", "found: {}", tr(69, 2, 1, 0));
+        //CALLT 0, D = 2: return f(x). RET 1, D = 3: return a, b. RET 1, D = 1: return.
+        assert!(tr(64, 0, 2, 0) == "return call(var(0), params(1->1), returns(multres(0)))", "found: {}", tr(64, 0, 2, 0));
+        assert!(tr(70, 1, 3, 0) == "return var(1), var(2)", "found: {}", tr(70, 1, 3, 0));
+        assert!(tr(70, 1, 1, 0) == "return", "found: {}", tr(70, 1, 1, 0));
+    }
+
     #[test]
     #[ignore]
     fn test_write() {
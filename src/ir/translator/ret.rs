@@ -11,11 +11,26 @@ impl Ret {
         let a = bci.a() as u16;
         let d = bci.d();
         match bci.op {
-            69          => Exp::Return(Box::new(Exp::Range((a+d-2) as u32, a as u32))), //TODO: RETM???
-            70          => Exp::Return(Box::new(Exp::Range((a+d-2) as u32, a as u32))), //RET
+            //RETM: return A, ..., A+D-1, MULTRES.
+            69          => {
+                let mut values = Ret::values(a, d);
+                values.push(Exp::MultRes(a + d));
+                Exp::Return(Box::new(Exp::List(values)))
+            },
+            //RET: return A, ..., A+D-2.
+            70          => match Ret::values(a, d - 1) {
+                values if values.is_empty() => Exp::Return(Box::new(Exp::Empty)),
+                mut values if values.len() == 1 => Exp::Return(Box::new(values.remove(0))),
+                values => Exp::Return(Box::new(Exp::List(values))),
+            },
             71          => Exp::Return(Box::new(Exp::Empty)), //RET0
             72          => Exp::Return(Box::new(Exp::Var(a))), //RET1
             _           => Exp::Error("ret".to_string()),
         }
     }
-}
\ No newline at end of file
+
+    //count slots starting at first, one expression each.
+    fn values(first: u16, count: u16) -> Vec<Exp> {
+        (first..first + count).map(Exp::Var).collect()
+    }
+}
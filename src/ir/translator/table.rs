@@ -8,13 +8,16 @@ use crate::{
 pub struct Table{}
 impl Table {
     pub fn table(bci: &Bci) -> Exp {
-        if bci.op == 60 { return Exp::Error("TSETM is unimplemented.".to_string()) }
+        if bci.op == 60 { return Table::tsetm(bci) }
     
         let a = Exp::Var(bci.a() as u16);
         
         if bci.op == 50 {
-            return Exp::Move(Box::new(a), Box::new(Exp::Table(Box::new(Exp::Empty), Box::new(Exp::Empty))));
-        } else if bci.op == 51 { return Exp::Error("TDUP is unimplemented.".to_string()) }
+            return Exp::Move(Box::new(a), Box::new(Exp::Constructor(Box::new(Exp::Empty), vec![])));
+        } else if bci.op == 51 { //TDUP: A = copy of the template table D.
+            let template = Box::new(Exp::Tab(bci.d()));
+            return Exp::Move(Box::new(a), Box::new(Exp::Constructor(template, vec![])));
+        }
         
         let tbl;
         let is_global = (52..=53).contains(&bci.op);
@@ -26,7 +29,7 @@ impl Table {
             let c = match bci.op {
                 54 | 57 => Box::new(Exp::Var(bci.c() as u16)),
                 55 | 58 => Box::new(Exp::Str(bci.c() as u16)),
-                56 | 59 => Box::new(Exp::Lit(bci.c() as u16)),
+                56 | 59 => Box::new(Exp::Lit(bci.c() as i32)), //unsigned byte index.
                 _       => Box::new(Exp::Error("table.c".to_string())),
             };
             tbl = Exp::Table(b, c);
@@ -39,4 +42,12 @@ impl Table {
             Exp::Move(Box::new(a), Box::new(tbl))
         }
     }
+
+    //TSETM: (A-1)[D], (A-1)[D+1], ... = A, A+1, ... up to MULTRES. D is a number constant holding the first index.
+    fn tsetm(bci: &Bci) -> Exp {
+        let a = bci.a() as u16;
+        let table = Box::new(Exp::Var(a - 1));
+        let base = Box::new(Exp::Num(bci.d()));
+        Exp::AppendMulti(table, base, Box::new(Exp::MultRes(a)))
+    }
 }
\ No newline at end of file
@@ -1,30 +1,153 @@
-#![feature(map_first_last)]
 #![allow(dead_code)] // REMOVE BEFORE RELEASE AND CORRECT WARNINGS.
 mod dis;
 mod ir;
 
-//use std::io::Write;
-//use std::fs::File;
-//use std::fs::OpenOptions;
+use std::collections::BTreeMap;
+use std::env;
+use std::process;
 
-//use crate::dis::prototyper::*;
-//use crate::ir::blocker::*;
+use crate::dis::prototyper::Prototyper;
+use crate::ir::{
+    blocker::Blocker,
+    cfg::Cfg,
+    locals::Locals,
+    rules::{PassManager, ProtoSummary, RuleContext},
+    ssa::Ssa,
+    structurer::Structurer,
+    translator::Translator,
+};
 
-const FILE_NAMES: [&str; 1] = [
-    "beam_system_client.lua",
-];
+const USAGE: &str = "usage: lj_decompiler <file> [--disable-rule <name>]... [--rule-order <name,name,...>] [--list-rules] [--deobfuscate] [--ssa]";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    file_name: Option<String>,
+    disabled_rules: Vec<String>,
+    rule_order: Vec<String>,
+    list_rules: bool,
+    deobfuscate: bool,
+    ssa: bool, //takes the IR through SSA form and back before locals are recovered.
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disable-rule" => options.disabled_rules.push(args.next().ok_or("--disable-rule needs a rule name.")?.to_string()),
+            "--rule-order"   => options.rule_order = args.next().ok_or("--rule-order needs a list of rule names.")?
+                .split(',').map(|s| s.trim().to_string()).collect(),
+            "--list-rules"   => options.list_rules = true,
+            "--deobfuscate"  => options.deobfuscate = true,
+            "--ssa"          => options.ssa = true,
+            a if a.starts_with("--") => return Err(format!("Unknown option: {}", a)),
+            _ => options.file_name = Some(arg.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+fn configure_passes(options: &Options) -> Result<PassManager, String> {
+    let mut pm = PassManager::default();
+    let order: Vec<&str> = options.rule_order.iter().map(|s| s.as_str()).collect();
+    pm.reorder(&order)?;
+    for name in options.disabled_rules.iter() {
+        pm.disable(name)?;
+    }
+    Ok(pm)
+}
 
 fn main() {
-    /*for name in FILE_NAMES.iter() {
-        let ptr = Prototyper::new(name);
-        let blr = Blocker{};
-        let ptbs = blr.make_prototype_blocks(&ptr);
-        let mut file = OpenOptions::new().truncate(true).open(&format!("{}.disasm", name)).unwrap();
-        assert!(ptbs.len() == 34, "beam_system_client should have 34 prototypes. Found: {}", ptbs.len());
-        for blocks in ptbs.iter() {
-            for b in blocks.iter() {
-                writeln!(&mut file, "{}", b);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (options, pm) = match parse_args(&args).and_then(|o| configure_passes(&o).map(|pm| (o, pm))) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(1);
+        },
+    };
+
+    if options.list_rules {
+        for name in pm.rule_names() {
+            println!("{}{}", name, if pm.is_enabled(name) { "" } else { " (disabled)" });
+        }
+        return;
+    }
+
+    let file_name = match options.file_name {
+        Some(f) => f,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        },
+    };
+
+    let mut ptr = Prototyper::new(&file_name);
+    ptr.deobfuscate = options.deobfuscate;
+    let mut protos: BTreeMap<usize, ProtoSummary> = BTreeMap::new();
+    while let Some(pt) = ptr.next() {
+        if !pt.faults.is_empty() {
+            eprintln!("Prototype {} is malformed:", pt.header.id);
+            for fault in pt.faults.iter() {
+                eprintln!("{}", fault);
             }
+            process::exit(1);
+        }
+        let blocks = Blocker{}.make_blocks(&pt);
+        let cfg = Cfg::new(&blocks);
+        let mut ir = Translator{}.translate_blocks(blocks);
+        if options.ssa {
+            Ssa::construct(&mut ir, &cfg);
+            Ssa::destruct(&mut ir, &cfg);
         }
-    }*/
-}
\ No newline at end of file
+        Locals::declare(&mut ir, &cfg, &pt);
+        let ctx = RuleContext::with_protos(&pt, &cfg, &protos);
+        pm.run(&mut ir, &ctx);
+
+        //Children come before their parents, which show them where they are created.
+        let mut stmts = Structurer::structure(ir, &cfg, &pt);
+        Structurer::inline_closures(&mut stmts, &ctx);
+        let mut summary = ProtoSummary::new(&pt);
+        summary.body = stmts;
+        protos.insert(pt.header.id, summary);
+    }
+
+    //The main chunk is the last prototype.
+    if let Some(main) = protos.values().last() {
+        for stmt in main.body.iter() {
+            println!("{}", stmt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args(&["dec.lua", "--disable-rule", "merge_literals", "--rule-order", "a, b"])).unwrap();
+        assert!(options.file_name == Some("dec.lua".to_string()));
+        assert!(options.disabled_rules == vec!["merge_literals"]);
+        assert!(options.rule_order == vec!["a", "b"]);
+        assert!(!options.deobfuscate);
+        assert!(parse_args(&args(&["--deobfuscate"])).unwrap().deobfuscate);
+        assert!(parse_args(&args(&["--ssa"])).unwrap().ssa);
+        assert!(parse_args(&args(&["--disable-rule"])).is_err());
+        assert!(parse_args(&args(&["--bogus"])).is_err());
+    }
+
+    #[test]
+    fn test_configure_passes() {
+        let options = parse_args(&args(&["--disable-rule", "merge_literals"])).unwrap();
+        let pm = configure_passes(&options).unwrap();
+        assert!(!pm.is_enabled("merge_literals"));
+
+        let options = parse_args(&args(&["--disable-rule", "unknown"])).unwrap();
+        assert!(configure_passes(&options).is_err());
+    }
+}